@@ -0,0 +1,88 @@
+/*
+ * Compares the run-length encoded and packed BWTs on a random genome, which has few repeats: the
+ * time to map a set of reads on one thread, and the size of the saved index. Both must map every
+ * read the same way.
+ *
+ *   cargo bench --bench packed_bwt
+ */
+use std::time::Instant;
+
+use read_mapping::fasta_parsing::FastaRecord;
+use read_mapping::index_file::BwtStorage;
+use read_mapping::read_mapping_index::{MapReadOptions, ReadMappingIndexBuilder};
+
+const GENOME_LENGTH: usize = 5_000_000;
+const READ_COUNT: usize = 100_000;
+const READ_LENGTH: usize = 100;
+// Each base of a read is substituted with probability 1 / `ERROR_RATE`
+const ERROR_RATE: usize = 100;
+
+// Deterministic xorshift generator, so every run maps the same reads
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        return (self.0 % bound as u64) as usize;
+    }
+
+    fn sequence(&mut self, length: usize) -> String {
+        return (0..length).map(|_| b"ACGT"[self.next(4)] as char).collect();
+    }
+}
+
+fn main() {
+    let mut random = Random(0x2545_f491_4f6c_dd1d);
+    let genome = random.sequence(GENOME_LENGTH);
+    let reads: Vec<String> = (0..READ_COUNT)
+        .map(|_| {
+            let start = random.next(GENOME_LENGTH - READ_LENGTH);
+            return genome[start..start + READ_LENGTH]
+                .chars()
+                .map(|nuc| match random.next(ERROR_RATE) {
+                    0 => b"ACGT"[random.next(4)] as char,
+                    _ => nuc,
+                })
+                .collect();
+        })
+        .collect();
+    let records = [FastaRecord::new("genome", &genome)];
+    let options = MapReadOptions::new(25, 3);
+
+    let mut first_results = None;
+    println!("BWT storage\tMapping seconds\tIndex file bytes");
+    for bwt_storage in [BwtStorage::RunLength, BwtStorage::Packed] {
+        let read_mapping_index = ReadMappingIndexBuilder::new()
+            .bwt_storage(bwt_storage)
+            .build(&records)
+            .unwrap();
+
+        let start = Instant::now();
+        let results: Vec<_> = reads
+            .iter()
+            .map(|read| read_mapping_index.map_read_with_options(read, &options))
+            .collect();
+        let mapping_time = start.elapsed();
+
+        let path = std::env::temp_dir().join(format!(
+            "read_mapping_bench_{bwt_storage:?}_{}.index",
+            std::process::id()
+        ));
+        read_mapping_index.to_file(path.to_str().unwrap()).unwrap();
+        let file_size = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+
+        println!(
+            "{bwt_storage:?}\t{:.1}\t{file_size}",
+            mapping_time.as_secs_f64()
+        );
+
+        assert!(results.iter().flatten().count() > READ_COUNT * 9 / 10);
+        match &first_results {
+            Some(first_results) => assert!(*first_results == results),
+            None => first_results = Some(results),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use std::cmp::max;
+
+use crate::cigar::{Cigar, CigarOp};
+use crate::fm_index::{MATCH_SCORE, MISMATCH_PENALTY, NON_NUCLEOTIDE};
+
+// Low enough to never be chosen, but high enough that subtracting penalties can't underflow
+const NEG_INFINITY: isize = isize::MIN / 2;
+
+#[derive(Debug, Clone)]
+pub struct AlignmentScoring {
+    pub match_score: isize,
+    pub mismatch_penalty: isize,
+    // A gap of length k costs gap_open_penalty + k * gap_extend_penalty
+    pub gap_open_penalty: isize,
+    pub gap_extend_penalty: isize,
+}
+
+impl Default for AlignmentScoring {
+    fn default() -> Self {
+        return AlignmentScoring {
+            match_score: MATCH_SCORE,
+            mismatch_penalty: MISMATCH_PENALTY,
+            gap_open_penalty: 5,
+            gap_extend_penalty: 3,
+        };
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Alignment {
+    pub score: isize,
+    // Covers the whole read, with any unaligned bases at either end soft clipped
+    pub cigar: Cigar,
+    // Number of mismatches, inserted bases and deleted bases
+    pub edit_distance: usize,
+    // Part of the read which aligned, outside of the soft clips
+    pub read_start: usize,
+    // Exclusive
+    pub read_end: usize,
+    // Offset into the reference where the alignment begins
+    pub reference_start: usize,
+    // Offsets from `reference_start` of each mismatch
+    pub mismatch_positions: Vec<usize>,
+}
+
+#[derive(Clone, Copy)]
+enum TracebackState {
+    // Reached cell by a match or mismatch
+    Diagonal,
+    // Reached cell by a gap in the read, ie a deletion
+    Deletion,
+    // Reached cell by a gap in the reference, ie an insertion
+    Insertion,
+}
+
+/*
+ * Smith-Waterman local alignment of `read` against `reference` with affine gap penalties
+ * (Gotoh's algorithm).
+ *
+ * Only cells within `band_width` of the diagonal are computed, where read[i] is expected to align
+ * with reference[i + diagonal]. This reduces the work from O(read * reference) to
+ * O(read * band_width), and limits the alignment to indels of at most `band_width` bases.
+ *
+ * Returns `None` if no part of the read aligns with a positive score.
+ */
+pub fn banded_local_alignment(
+    read: &str,
+    reference: &str,
+    diagonal: isize,
+    band_width: usize,
+    scoring: &AlignmentScoring,
+) -> Option<Alignment> {
+    let read = read.as_bytes();
+    let reference = reference.as_bytes();
+
+    let band = Band {
+        diagonal,
+        band_width,
+        reference_length: reference.len(),
+    };
+
+    /*
+     * The DP matrices have a row for each prefix of the read, but only store the cells in the band
+     * for each row. `best` holds the score of the best alignment ending at each cell, while
+     * `deletion` and `insertion` hold the best score for alignments ending in a gap.
+     */
+    let cell_count = (read.len() + 1) * band.width();
+    let mut best = vec![NEG_INFINITY; cell_count];
+    let mut deletion = vec![NEG_INFINITY; cell_count];
+    let mut insertion = vec![NEG_INFINITY; cell_count];
+
+    let gap_open_cost = scoring.gap_open_penalty + scoring.gap_extend_penalty;
+
+    // Score, row and band offset of the best cell
+    let mut max_cell = (0, 0, 0);
+
+    for read_index in 0..=read.len() {
+        for band_offset in 0..band.width() {
+            let Some(reference_index) = band.reference_index(read_index, band_offset) else {
+                continue;
+            };
+            let cell = read_index * band.width() + band_offset;
+
+            // Local alignments can start anywhere
+            if read_index == 0 || reference_index == 0 {
+                best[cell] = 0;
+                continue;
+            }
+
+            // The cell to the left is on the same row one place earlier in the band
+            if band_offset > 0 {
+                let left_cell = cell - 1;
+                deletion[cell] = max(
+                    best[left_cell] - gap_open_cost,
+                    deletion[left_cell] - scoring.gap_extend_penalty,
+                );
+            }
+
+            // The cell above is on the previous row one place later in the band
+            if band_offset + 1 < band.width() {
+                let up_cell = cell - band.width() + 1;
+                insertion[cell] = max(
+                    best[up_cell] - gap_open_cost,
+                    insertion[up_cell] - scoring.gap_extend_penalty,
+                );
+            }
+
+            // The diagonal cell is on the previous row at the same place in the band
+            let diagonal_cell = cell - band.width();
+            let diagonal_score = best[diagonal_cell]
+                + substitution_score(
+                    read[read_index - 1],
+                    reference[reference_index - 1],
+                    scoring,
+                );
+
+            best[cell] = max(max(0, diagonal_score), max(deletion[cell], insertion[cell]));
+
+            if best[cell] > max_cell.0 {
+                max_cell = (best[cell], read_index, band_offset);
+            }
+        }
+    }
+
+    let (score, read_end, band_offset) = max_cell;
+
+    if score == 0 {
+        return None;
+    }
+
+    // Trace back from the best cell until we reach the start of the local alignment
+    let mut read_index = read_end;
+    let mut band_offset = band_offset;
+    let mut reference_index = band.reference_index(read_index, band_offset).unwrap();
+
+    let mut reversed_ops = Vec::new();
+    let mut mismatch_reference_indices = Vec::new();
+    let mut state = TracebackState::Diagonal;
+
+    loop {
+        let cell = read_index * band.width() + band_offset;
+
+        match state {
+            TracebackState::Diagonal => {
+                if best[cell] == 0 {
+                    break;
+                }
+
+                if best[cell] == deletion[cell] {
+                    state = TracebackState::Deletion;
+                    continue;
+                }
+
+                if best[cell] == insertion[cell] {
+                    state = TracebackState::Insertion;
+                    continue;
+                }
+
+                if read[read_index - 1] != reference[reference_index - 1] {
+                    mismatch_reference_indices.push(reference_index - 1);
+                }
+
+                reversed_ops.push(CigarOp::Match);
+                read_index -= 1;
+                reference_index -= 1;
+            }
+            TracebackState::Deletion => {
+                let left_cell = cell - 1;
+                if deletion[cell] == best[left_cell] - gap_open_cost {
+                    state = TracebackState::Diagonal;
+                }
+
+                reversed_ops.push(CigarOp::Deletion);
+                reference_index -= 1;
+                band_offset -= 1;
+            }
+            TracebackState::Insertion => {
+                let up_cell = cell - band.width() + 1;
+                if insertion[cell] == best[up_cell] - gap_open_cost {
+                    state = TracebackState::Diagonal;
+                }
+
+                reversed_ops.push(CigarOp::Insertion);
+                read_index -= 1;
+                band_offset += 1;
+            }
+        }
+    }
+
+    let read_start = read_index;
+    let reference_start = reference_index;
+
+    let mut cigar = Cigar::new();
+    cigar.push(CigarOp::SoftClip, read_start);
+    for op in reversed_ops.into_iter().rev() {
+        cigar.push(op, 1);
+    }
+    cigar.push(CigarOp::SoftClip, read.len() - read_end);
+
+    let indel_length: usize = cigar
+        .entries()
+        .iter()
+        .filter(|entry| matches!(entry.op, CigarOp::Insertion | CigarOp::Deletion))
+        .map(|entry| entry.count)
+        .sum();
+
+    let mismatch_positions: Vec<usize> = mismatch_reference_indices
+        .into_iter()
+        .rev()
+        .map(|index| index - reference_start)
+        .collect();
+
+    return Some(Alignment {
+        score,
+        cigar,
+        edit_distance: mismatch_positions.len() + indel_length,
+        read_start,
+        read_end,
+        reference_start,
+        mismatch_positions,
+    });
+}
+
+// Maps between the columns of a row of the DP matrix and the offsets of the band stored for it
+struct Band {
+    diagonal: isize,
+    band_width: usize,
+    reference_length: usize,
+}
+
+impl Band {
+    fn width(&self) -> usize {
+        return 2 * self.band_width + 1;
+    }
+
+    // Column of the DP matrix for the cell at `band_offset` in row `read_index`, if it exists
+    fn reference_index(&self, read_index: usize, band_offset: usize) -> Option<usize> {
+        let reference_index =
+            read_index as isize + self.diagonal - self.band_width as isize + band_offset as isize;
+
+        if reference_index < 0 || reference_index as usize > self.reference_length {
+            return None;
+        }
+
+        return Some(reference_index as usize);
+    }
+}
+
+fn substitution_score(read_nuc: u8, reference_nuc: u8, scoring: &AlignmentScoring) -> isize {
+    if read_nuc == reference_nuc && reference_nuc != NON_NUCLEOTIDE as u8 {
+        return scoring.match_score;
+    }
+    return -scoring.mismatch_penalty;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn align(read: &str, reference: &str, diagonal: isize) -> Alignment {
+        return banded_local_alignment(read, reference, diagonal, 3, &AlignmentScoring::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn exact_match() {
+        let alignment = align("ACGTACGT", "TTACGTACGTTT", 2);
+
+        assert_eq!(alignment.score, 8);
+        assert_eq!(alignment.cigar.to_string(), "8M");
+        assert_eq!(alignment.edit_distance, 0);
+        assert_eq!(alignment.reference_start, 2);
+    }
+
+    #[test]
+    fn indels() {
+        let reference = "GATTACAGATCCGTAGGCTTAACG";
+
+        // Missing the G at position 12 of the reference
+        let alignment = align("GATTACAGATCCTAGGCTTAACG", reference, 0);
+        assert_eq!(alignment.score, 23 - 8);
+        assert_eq!(alignment.cigar.to_string(), "12M1D11M");
+        assert_eq!(alignment.edit_distance, 1);
+        assert_eq!(alignment.reference_start, 0);
+
+        // Extra A after position 12 of the reference
+        let alignment = align("GATTACAGATCCAGTAGGCTTAACG", reference, 0);
+        assert_eq!(alignment.score, 24 - 8);
+        assert_eq!(alignment.cigar.to_string(), "12M1I12M");
+        assert_eq!(alignment.edit_distance, 1);
+    }
+
+    #[test]
+    fn mismatches() {
+        let reference = "GATTACAGATCCGTAGGCTTAACG";
+
+        // Mismatch in the middle is kept
+        let alignment = align("GATTACAGATCCCTAGGCTTAACG", reference, 0);
+        assert_eq!(alignment.score, 23 - 4);
+        assert_eq!(alignment.cigar.to_string(), "24M");
+        assert_eq!(alignment.mismatch_positions, vec![12]);
+        assert_eq!(alignment.edit_distance, 1);
+
+        // Mismatch at the end is soft clipped
+        let alignment = align("GATTACAGATCCGTAGGCTTAACC", reference, 0);
+        assert_eq!(alignment.score, 23);
+        assert_eq!(alignment.cigar.to_string(), "23M1S");
+        assert_eq!((alignment.read_start, alignment.read_end), (0, 23));
+        assert_eq!(alignment.edit_distance, 0);
+    }
+
+    #[test]
+    fn no_alignment() {
+        let alignment =
+            banded_local_alignment("AAAA", "CCCCCC", 1, 2, &AlignmentScoring::default());
+        assert_eq!(alignment, None);
+    }
+}
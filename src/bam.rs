@@ -0,0 +1,778 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+
+use crate::cigar::CigarOp;
+use crate::fastq_parsing::FastqRecord;
+use crate::paired_end::MateInfo;
+use crate::read_mapping_index::{Contig, MapReadResult, Strand};
+use crate::sam::{
+    oriented_seq_and_qual, placements, sam_flag, sam_header, Placement, FLAG_UNMAPPED,
+};
+
+// Uncompressed data in each BGZF block is limited so the compressed block always fits in 64KiB
+const MAX_BLOCK_DATA_SIZE: usize = 0xff00;
+
+// Empty BGZF block marking the end of the file
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+// 4-bit codes for the bases of a BAM record's sequence
+const SEQ_CODES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+// Bin holding the index metadata for a reference, rather than records
+const PSEUDO_BIN: u32 = 37450;
+
+// Each entry of the linear index covers 16KiB of the reference
+const LINEAR_INDEX_SHIFT: i64 = 14;
+
+/*
+ * Writes data in BGZF format, a series of independently gzipped blocks of at most 64KiB. Positions
+ * in the uncompressed data are given by virtual offsets, which combine the position of a block in
+ * the file with the offset into its uncompressed data.
+ */
+pub struct BgzfWriter<W: Write> {
+    writer: W,
+    // Uncompressed data for the current block
+    block_data: Vec<u8>,
+    // Position of the current block in the compressed file
+    block_address: u64,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(writer: W) -> Self {
+        return BgzfWriter {
+            writer,
+            block_data: Vec::with_capacity(MAX_BLOCK_DATA_SIZE),
+            block_address: 0,
+        };
+    }
+
+    pub fn virtual_offset(&self) -> u64 {
+        return (self.block_address << 16) | self.block_data.len() as u64;
+    }
+
+    // Compresses and writes the current block, if it holds any data
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.block_data.is_empty() {
+            return Ok(());
+        }
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.block_data)?;
+        let compressed_data = encoder.finish()?;
+
+        let mut crc = Crc::new();
+        crc.update(&self.block_data);
+
+        // gzip header with the BC extra subfield holding the total block size - 1
+        let block_size = 18 + compressed_data.len() + 8;
+        self.writer.write_all(&[
+            0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+            0x02, 0x00,
+        ])?;
+        self.writer
+            .write_all(&(block_size as u16 - 1).to_le_bytes())?;
+        self.writer.write_all(&compressed_data)?;
+        self.writer.write_all(&crc.sum().to_le_bytes())?;
+        self.writer
+            .write_all(&(self.block_data.len() as u32).to_le_bytes())?;
+
+        self.block_address += block_size as u64;
+        self.block_data.clear();
+
+        return Ok(());
+    }
+
+    // Writes any buffered data and the end of file marker
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        self.writer.write_all(&BGZF_EOF)?;
+        self.writer.flush()?;
+        return Ok(self.writer);
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = buf.len().min(MAX_BLOCK_DATA_SIZE - self.block_data.len());
+        self.block_data.extend_from_slice(&buf[..length]);
+
+        if self.block_data.len() == MAX_BLOCK_DATA_SIZE {
+            self.write_block()?;
+        }
+
+        return Ok(length);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        return self.writer.flush();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SortOptions {
+    // Records are sorted in chunks of roughly this many bytes, which are then merged from disk
+    pub max_memory: usize,
+}
+
+impl Default for SortOptions {
+    fn default() -> Self {
+        return SortOptions {
+            max_memory: 768 * 1024 * 1024,
+        };
+    }
+}
+
+/*
+ * Writes mapped reads in the BAM format, the compressed binary equivalent of SAM. When sorting by
+ * coordinate, a `.bai` index is also written alongside the BAM file.
+ */
+pub struct BamWriter {
+    path: PathBuf,
+    bgzf_writer: BgzfWriter<BufWriter<File>>,
+    reference_count: usize,
+    sorter: Option<RecordSorter>,
+}
+
+impl BamWriter {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        contigs: &[Contig],
+        sort: Option<SortOptions>,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut bgzf_writer = BgzfWriter::new(BufWriter::new(File::create(&path)?));
+
+        let sort_order = match sort {
+            Some(_) => "coordinate",
+            None => "unsorted",
+        };
+        let header_text = sam_header(contigs, sort_order);
+
+        bgzf_writer.write_all(b"BAM\x01")?;
+        bgzf_writer.write_all(&(header_text.len() as u32).to_le_bytes())?;
+        bgzf_writer.write_all(header_text.as_bytes())?;
+
+        bgzf_writer.write_all(&(contigs.len() as u32).to_le_bytes())?;
+        for contig in contigs {
+            bgzf_writer.write_all(&(contig.name.len() as u32 + 1).to_le_bytes())?;
+            bgzf_writer.write_all(contig.name.as_bytes())?;
+            bgzf_writer.write_all(&[0])?;
+            bgzf_writer.write_all(&(contig.length as u32).to_le_bytes())?;
+        }
+
+        let sorter = sort.map(|sort_options| RecordSorter::new(&path, sort_options));
+
+        return Ok(BamWriter {
+            path,
+            bgzf_writer,
+            reference_count: contigs.len(),
+            sorter,
+        });
+    }
+
+    // Writes a record for the read, which is unmapped if `result` is `None`
+    pub fn write_record(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+    ) -> io::Result<()> {
+        return self.write_bam_record(record, result, None);
+    }
+
+    // Writes a record for one mate of a paired read
+    pub fn write_mate(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+        mate_info: &MateInfo,
+    ) -> io::Result<()> {
+        return self.write_bam_record(record, result, Some(mate_info));
+    }
+
+    fn write_bam_record(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+        mate_info: Option<&MateInfo>,
+    ) -> io::Result<()> {
+        let bam_record = encode_record(record, result, mate_info)?;
+
+        match &mut self.sorter {
+            Some(sorter) => return sorter.push(bam_record),
+            None => return write_bam_record(&mut self.bgzf_writer, &bam_record),
+        }
+    }
+
+    // Writes any records waiting to be sorted, then the index if the records were sorted
+    pub fn finish(mut self) -> io::Result<()> {
+        let Some(sorter) = self.sorter.take() else {
+            self.bgzf_writer.finish()?;
+            return Ok(());
+        };
+
+        let mut index = BaiBuilder::new(self.reference_count);
+
+        sorter.merge(|bam_record| {
+            let start_offset = self.bgzf_writer.virtual_offset();
+            write_bam_record(&mut self.bgzf_writer, &bam_record)?;
+            index.add_record(&bam_record, start_offset, self.bgzf_writer.virtual_offset());
+            return Ok(());
+        })?;
+
+        self.bgzf_writer.finish()?;
+
+        let mut index_path = self.path.into_os_string();
+        index_path.push(".bai");
+        let mut index_writer = BufWriter::new(File::create(index_path)?);
+        index.write(&mut index_writer)?;
+        return index_writer.flush();
+    }
+}
+
+fn write_bam_record<W: Write>(writer: &mut W, bam_record: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bam_record.len() as u32).to_le_bytes())?;
+    return writer.write_all(bam_record);
+}
+
+/*
+ * Encodes a read as a BAM alignment record, without the leading `block_size`. Reads from the
+ * reverse strand are stored as they appear in the forwards genome, as in SAM.
+ */
+fn encode_record(
+    record: &FastqRecord,
+    result: Option<&MapReadResult>,
+    mate_info: Option<&MateInfo>,
+) -> io::Result<Vec<u8>> {
+    // Names are NUL terminated with the length stored in a single byte
+    if record.name.len() > 254 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("read name {} is too long for BAM", record.name),
+        ));
+    }
+
+    let flag = sam_flag(result, mate_info);
+    let (mapq, strand) = match result {
+        Some(result) => (result.mapq, result.strand),
+        None => (0, Strand::Forward),
+    };
+
+    // Reference ID and 0-based position, or -1 for neither
+    let (placement, mate_placement) = placements(result, mate_info);
+    let bam_placement = |placement: Placement| match placement {
+        Some((contig_index, position)) => (contig_index as i32, position as i32),
+        None => (-1, -1),
+    };
+    let (ref_id, pos) = bam_placement(placement);
+    let (next_ref_id, next_pos) = bam_placement(mate_placement);
+    let template_length = mate_info.map_or(0, |mate_info| mate_info.template_length as i32);
+
+    let cigar_ops: Vec<u32> = match result {
+        Some(result) => result
+            .cigar
+            .entries()
+            .iter()
+            .map(|entry| ((entry.count as u32) << 4) | cigar_op_code(entry.op))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    // Unmapped reads are binned as if they covered a single base at `pos`
+    let genome_length = result.map_or(1, |result| result.cigar.genome_length().max(1));
+    let bin = region_to_bin(pos as i64, pos as i64 + genome_length as i64);
+
+    let (seq, qual) = oriented_seq_and_qual(record, strand);
+
+    let mut bam_record = Vec::with_capacity(32 + record.name.len() + 1 + 4 * cigar_ops.len());
+    bam_record.extend_from_slice(&ref_id.to_le_bytes());
+    bam_record.extend_from_slice(&pos.to_le_bytes());
+    bam_record.push(record.name.len() as u8 + 1);
+    bam_record.push(mapq);
+    bam_record.extend_from_slice(&bin.to_le_bytes());
+    bam_record.extend_from_slice(&(cigar_ops.len() as u16).to_le_bytes());
+    bam_record.extend_from_slice(&flag.to_le_bytes());
+    bam_record.extend_from_slice(&(seq.len() as u32).to_le_bytes());
+    bam_record.extend_from_slice(&next_ref_id.to_le_bytes());
+    bam_record.extend_from_slice(&next_pos.to_le_bytes());
+    bam_record.extend_from_slice(&template_length.to_le_bytes());
+
+    bam_record.extend_from_slice(record.name.as_bytes());
+    bam_record.push(0);
+
+    for cigar_op in cigar_ops {
+        bam_record.extend_from_slice(&cigar_op.to_le_bytes());
+    }
+
+    // Two bases per byte, with the first base in the high nibble
+    for bases in seq.as_bytes().chunks(2) {
+        let high = seq_code(bases[0]);
+        let low = bases.get(1).map_or(0, |&base| seq_code(base));
+        bam_record.push((high << 4) | low);
+    }
+
+    // Qualities are stored without the Phred+33 offset, or as 0xff if unknown
+    if qual.is_empty() {
+        bam_record.extend(std::iter::repeat_n(0xff, seq.len()));
+    } else {
+        bam_record.extend(qual.bytes().map(|qual| qual.saturating_sub(33)));
+    }
+
+    if let Some(result) = result {
+        bam_record.extend_from_slice(b"ASi");
+        bam_record.extend_from_slice(&(result.alignment_score as i32).to_le_bytes());
+        bam_record.extend_from_slice(b"NMi");
+        bam_record.extend_from_slice(&(result.edit_distance as i32).to_le_bytes());
+    }
+
+    return Ok(bam_record);
+}
+
+fn cigar_op_code(op: CigarOp) -> u32 {
+    match op {
+        CigarOp::Match => 0,
+        CigarOp::Insertion => 1,
+        CigarOp::Deletion => 2,
+        CigarOp::Skip => 3,
+        CigarOp::SoftClip => 4,
+    }
+}
+
+fn seq_code(base: u8) -> u8 {
+    return SEQ_CODES
+        .iter()
+        .position(|&code| code == base.to_ascii_uppercase())
+        .unwrap_or(15) as u8; // N
+}
+
+/*
+ * Smallest bin of the BAM binning scheme containing [start, end). Bins form a tree where each
+ * level splits the bins above into 8, from the whole reference down to 16KiB.
+ */
+fn region_to_bin(start: i64, end: i64) -> u16 {
+    let end = end - 1;
+
+    for (level_shift, level_offset) in [(14, 4681), (17, 585), (20, 73), (23, 9), (26, 1)] {
+        if start >> level_shift == end >> level_shift {
+            return (level_offset + (start >> level_shift)) as u16;
+        }
+    }
+
+    return 0;
+}
+
+// Position of each field in an encoded record
+fn record_ref_id(bam_record: &[u8]) -> i32 {
+    return i32::from_le_bytes(bam_record[0..4].try_into().unwrap());
+}
+
+fn record_pos(bam_record: &[u8]) -> i32 {
+    return i32::from_le_bytes(bam_record[4..8].try_into().unwrap());
+}
+
+fn record_bin(bam_record: &[u8]) -> u16 {
+    return u16::from_le_bytes(bam_record[10..12].try_into().unwrap());
+}
+
+fn record_flag(bam_record: &[u8]) -> u16 {
+    return u16::from_le_bytes(bam_record[14..16].try_into().unwrap());
+}
+
+// Exclusive end of the genome covered by the record, according to its CIGAR
+fn record_end(bam_record: &[u8]) -> i64 {
+    let name_length = bam_record[8] as usize;
+    let cigar_op_count = u16::from_le_bytes(bam_record[12..14].try_into().unwrap()) as usize;
+    let cigar_start = 32 + name_length;
+
+    let genome_length: i64 = bam_record[cigar_start..cigar_start + 4 * cigar_op_count]
+        .chunks(4)
+        .map(|cigar_op| u32::from_le_bytes(cigar_op.try_into().unwrap()))
+        // M, D, N, = and X consume the genome
+        .filter(|cigar_op| matches!(cigar_op & 0xf, 0 | 2 | 3 | 7 | 8))
+        .map(|cigar_op| (cigar_op >> 4) as i64)
+        .sum();
+
+    return record_pos(bam_record) as i64 + genome_length.max(1);
+}
+
+// Reads without a position sort after all other reads
+fn sort_key(bam_record: &[u8]) -> (u32, u32) {
+    return (
+        record_ref_id(bam_record) as u32,
+        record_pos(bam_record) as u32,
+    );
+}
+
+/*
+ * Sorts records by coordinate using a bounded amount of memory. Records are held in memory until
+ * they reach the memory limit, then sorted and written to a temporary chunk file. The chunks are
+ * merged once all records have been added, and removed when the sorter is dropped, even if
+ * sorting fails part way through.
+ */
+struct RecordSorter {
+    path_prefix: PathBuf,
+    options: SortOptions,
+    records: Vec<Vec<u8>>,
+    memory: usize,
+    chunk_paths: Vec<PathBuf>,
+}
+
+impl RecordSorter {
+    fn new(path: &Path, options: SortOptions) -> Self {
+        return RecordSorter {
+            path_prefix: path.to_path_buf(),
+            options,
+            records: Vec::new(),
+            memory: 0,
+            chunk_paths: Vec::new(),
+        };
+    }
+
+    fn push(&mut self, bam_record: Vec<u8>) -> io::Result<()> {
+        self.memory += bam_record.len() + size_of::<Vec<u8>>();
+        self.records.push(bam_record);
+
+        if self.memory >= self.options.max_memory {
+            self.write_chunk()?;
+        }
+
+        return Ok(());
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        let mut chunk_path = self.path_prefix.clone().into_os_string();
+        chunk_path.push(format!(".sort.{}.tmp", self.chunk_paths.len()));
+        let chunk_path = PathBuf::from(chunk_path);
+
+        // Stable, so reads at the same position stay in the order they were mapped
+        self.records.sort_by_key(|bam_record| sort_key(bam_record));
+
+        // Recorded before it's created, so a partly written chunk is still removed
+        self.chunk_paths.push(chunk_path.clone());
+
+        let mut chunk_writer = BufWriter::new(File::create(&chunk_path)?);
+        for bam_record in self.records.drain(..) {
+            write_bam_record(&mut chunk_writer, &bam_record)?;
+        }
+        chunk_writer.flush()?;
+
+        self.memory = 0;
+
+        return Ok(());
+    }
+
+    // Passes every record to `output` in coordinate order
+    fn merge<F>(mut self, mut output: F) -> io::Result<()>
+    where
+        F: FnMut(Vec<u8>) -> io::Result<()>,
+    {
+        // Everything fit in memory, so no need to go via disk
+        if self.chunk_paths.is_empty() {
+            self.records.sort_by_key(|bam_record| sort_key(bam_record));
+            for bam_record in self.records.drain(..) {
+                output(bam_record)?;
+            }
+            return Ok(());
+        }
+
+        if !self.records.is_empty() {
+            self.write_chunk()?;
+        }
+
+        let mut chunk_readers = self
+            .chunk_paths
+            .iter()
+            .map(|chunk_path| Ok(BufReader::new(File::open(chunk_path)?)))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // The next record from each chunk, ordered by position and then by chunk
+        let mut next_records = BinaryHeap::new();
+        for (chunk_index, chunk_reader) in chunk_readers.iter_mut().enumerate() {
+            if let Some(bam_record) = read_bam_record(chunk_reader)? {
+                next_records.push(Reverse((sort_key(&bam_record), chunk_index, bam_record)));
+            }
+        }
+
+        while let Some(Reverse((_, chunk_index, bam_record))) = next_records.pop() {
+            output(bam_record)?;
+
+            if let Some(bam_record) = read_bam_record(&mut chunk_readers[chunk_index])? {
+                next_records.push(Reverse((sort_key(&bam_record), chunk_index, bam_record)));
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+impl Drop for RecordSorter {
+    fn drop(&mut self) {
+        // Nothing can be done about a chunk which can't be removed, and it may never have existed
+        for chunk_path in &self.chunk_paths {
+            let _ = fs::remove_file(chunk_path);
+        }
+    }
+}
+
+// Reads a record written by `write_bam_record`, or returns `None` at the end of the file
+fn read_bam_record<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+
+    let mut block_size = [0; 4];
+    reader.read_exact(&mut block_size)?;
+
+    let mut bam_record = vec![0; u32::from_le_bytes(block_size) as usize];
+    reader.read_exact(&mut bam_record)?;
+
+    return Ok(Some(bam_record));
+}
+
+// Index of the records of a single reference
+#[derive(Default)]
+struct ReferenceIndex {
+    // Virtual offset ranges of the records in each bin
+    bins: BTreeMap<u32, Vec<(u64, u64)>>,
+    // Virtual offset of the first record overlapping each 16KiB window
+    linear_index: Vec<u64>,
+    start_offset: u64,
+    end_offset: u64,
+    mapped_count: u64,
+    unmapped_count: u64,
+}
+
+// Builds a BAI index as coordinate sorted records are written
+struct BaiBuilder {
+    references: Vec<ReferenceIndex>,
+    // Reads without a position
+    unplaced_count: u64,
+}
+
+impl BaiBuilder {
+    fn new(reference_count: usize) -> Self {
+        return BaiBuilder {
+            references: (0..reference_count)
+                .map(|_| ReferenceIndex::default())
+                .collect(),
+            unplaced_count: 0,
+        };
+    }
+
+    fn add_record(&mut self, bam_record: &[u8], start_offset: u64, end_offset: u64) {
+        let ref_id = record_ref_id(bam_record);
+        if ref_id < 0 {
+            self.unplaced_count += 1;
+            return;
+        }
+
+        let reference = &mut self.references[ref_id as usize];
+
+        let chunks = reference
+            .bins
+            .entry(record_bin(bam_record) as u32)
+            .or_default();
+        match chunks.last_mut() {
+            // Records next to each other in the file share a chunk
+            Some(last_chunk) if last_chunk.1 == start_offset => last_chunk.1 = end_offset,
+            _ => chunks.push((start_offset, end_offset)),
+        }
+
+        let first_window = (record_pos(bam_record) as i64 >> LINEAR_INDEX_SHIFT) as usize;
+        let last_window = ((record_end(bam_record) - 1) >> LINEAR_INDEX_SHIFT) as usize;
+        if reference.linear_index.len() <= last_window {
+            reference.linear_index.resize(last_window + 1, 0);
+        }
+        for window in first_window..=last_window {
+            if reference.linear_index[window] == 0 {
+                reference.linear_index[window] = start_offset;
+            }
+        }
+
+        if reference.mapped_count + reference.unmapped_count == 0 {
+            reference.start_offset = start_offset;
+        }
+        reference.end_offset = end_offset;
+
+        if record_flag(bam_record) & FLAG_UNMAPPED == 0 {
+            reference.mapped_count += 1;
+        } else {
+            reference.unmapped_count += 1;
+        }
+    }
+
+    fn write<W: Write>(mut self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(b"BAI\x01")?;
+        writer.write_all(&(self.references.len() as i32).to_le_bytes())?;
+
+        for reference in &mut self.references {
+            let has_records = reference.mapped_count + reference.unmapped_count > 0;
+
+            let bin_count = reference.bins.len() + usize::from(has_records);
+            writer.write_all(&(bin_count as i32).to_le_bytes())?;
+
+            for (bin, chunks) in &reference.bins {
+                writer.write_all(&bin.to_le_bytes())?;
+                writer.write_all(&(chunks.len() as i32).to_le_bytes())?;
+                for (chunk_start, chunk_end) in chunks {
+                    writer.write_all(&chunk_start.to_le_bytes())?;
+                    writer.write_all(&chunk_end.to_le_bytes())?;
+                }
+            }
+
+            if has_records {
+                writer.write_all(&PSEUDO_BIN.to_le_bytes())?;
+                writer.write_all(&2i32.to_le_bytes())?;
+                for value in [
+                    reference.start_offset,
+                    reference.end_offset,
+                    reference.mapped_count,
+                    reference.unmapped_count,
+                ] {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+            }
+
+            // Windows without records of their own start from the previous window's records
+            let mut previous_offset = 0;
+            for offset in &mut reference.linear_index {
+                if *offset == 0 {
+                    *offset = previous_offset;
+                }
+                previous_offset = *offset;
+            }
+
+            writer.write_all(&(reference.linear_index.len() as i32).to_le_bytes())?;
+            for offset in &reference.linear_index {
+                writer.write_all(&offset.to_le_bytes())?;
+            }
+        }
+
+        return writer.write_all(&self.unplaced_count.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fasta_parsing::FastaRecord;
+    use crate::read_mapping_index::ReadMappingIndex;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn bins() {
+        assert_eq!(region_to_bin(0, 1), 4681);
+        assert_eq!(region_to_bin(16384, 16400), 4682);
+        assert_eq!(region_to_bin(16000, 17000), 585);
+        assert_eq!(region_to_bin(-1, 0), 4680);
+    }
+
+    #[test]
+    fn write_sorted_bam() {
+        let read_mapping_index = ReadMappingIndex::new(&[
+            FastaRecord::new(
+                "chromosome",
+                "ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT",
+            ),
+            FastaRecord::new("plasmid", "GGACTGGCATTTTTATTACACTCAGAAACAGAACTCGGGT"),
+        ])
+        .unwrap();
+
+        let reads = [
+            ("plasmid", "TTATTACACTCAGAA"),
+            ("unmapped", "CCCCCCCCCCCCCCC"),
+            ("chromosome_reverse", "AGACGTAAACGAAGG"),
+            ("chromosome", "ATACTTTATCAAATG"),
+        ];
+
+        let path = std::env::temp_dir().join(format!("read_mapping_{}.bam", std::process::id()));
+
+        // A tiny memory limit forces the records to be merged from several chunks
+        let sort_options = SortOptions { max_memory: 1 };
+        let mut bam_writer =
+            BamWriter::create(&path, read_mapping_index.contigs(), Some(sort_options)).unwrap();
+        for (name, seq) in reads {
+            let record = FastqRecord {
+                name: String::from(name),
+                comment: None,
+                seq: String::from(seq),
+                qual: String::from("IIIIIIIIIIIIIII"),
+            };
+            let result = read_mapping_index.map_read(seq, 10, 1);
+            bam_writer.write_record(&record, result.as_ref()).unwrap();
+        }
+        bam_writer.finish().unwrap();
+
+        let mut bam = Vec::new();
+        MultiGzDecoder::new(File::open(&path).unwrap())
+            .read_to_end(&mut bam)
+            .unwrap();
+        assert_eq!(&bam[..4], b"BAM\x01");
+
+        // Skip the header to the records
+        let text_length = u32::from_le_bytes(bam[4..8].try_into().unwrap()) as usize;
+        let mut records = &bam[8 + text_length + 4..];
+        for contig in read_mapping_index.contigs() {
+            records = &records[4 + contig.name.len() + 1 + 4..];
+        }
+
+        let mut names_and_positions = Vec::new();
+        while let Some(bam_record) = read_bam_record(&mut records).unwrap() {
+            let name_length = bam_record[8] as usize;
+            let name = String::from_utf8(bam_record[32..32 + name_length - 1].to_vec()).unwrap();
+            names_and_positions.push((name, record_ref_id(&bam_record), record_pos(&bam_record)));
+        }
+
+        assert_eq!(
+            names_and_positions,
+            vec![
+                (String::from("chromosome"), 0, 0),
+                (String::from("chromosome_reverse"), 0, 26),
+                (String::from("plasmid"), 1, 12),
+                (String::from("unmapped"), -1, -1),
+            ]
+        );
+
+        let mut index_path = path.clone().into_os_string();
+        index_path.push(".bai");
+        let index = fs::read(&index_path).unwrap();
+        assert_eq!(&index[..4], b"BAI\x01");
+        assert_eq!(i32::from_le_bytes(index[4..8].try_into().unwrap()), 2);
+        // One read without a position
+        assert_eq!(
+            u64::from_le_bytes(index[index.len() - 8..].try_into().unwrap()),
+            1
+        );
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&index_path).unwrap();
+    }
+
+    #[test]
+    fn sort_chunks_removed_on_error() {
+        let path =
+            std::env::temp_dir().join(format!("read_mapping_{}_failed.bam", std::process::id()));
+
+        let mut sorter = RecordSorter::new(&path, SortOptions { max_memory: 1 });
+        for _ in 0..3 {
+            sorter.push(vec![0; 36]).unwrap();
+        }
+        let chunk_paths = sorter.chunk_paths.clone();
+        assert_eq!(chunk_paths.len(), 3);
+        assert!(chunk_paths.iter().all(|chunk_path| chunk_path.exists()));
+
+        let result = sorter.merge(|_| Err(io::Error::other("disk full")));
+        assert!(result.is_err());
+        assert!(chunk_paths.iter().all(|chunk_path| !chunk_path.exists()));
+    }
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CigarOp {
+    // Alignment match - the read and genome may still disagree at this position
+    Match,
+    // Bases in the read which are not in the genome
+    Insertion,
+    // Bases in the genome which are not in the read
+    Deletion,
+    // Bases in the genome skipped over by the read, eg an intron
+    Skip,
+    // Bases at the ends of the read which are not part of the alignment
+    SoftClip,
+}
+
+impl CigarOp {
+    pub fn symbol(&self) -> char {
+        match self {
+            CigarOp::Match => 'M',
+            CigarOp::Insertion => 'I',
+            CigarOp::Deletion => 'D',
+            CigarOp::Skip => 'N',
+            CigarOp::SoftClip => 'S',
+        }
+    }
+
+    pub fn consumes_read(&self) -> bool {
+        return !matches!(self, CigarOp::Deletion | CigarOp::Skip);
+    }
+
+    pub fn consumes_genome(&self) -> bool {
+        return matches!(self, CigarOp::Match | CigarOp::Deletion | CigarOp::Skip);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CigarEntry {
+    pub op: CigarOp,
+    pub count: usize,
+}
+
+// Run-length encoded description of how a read aligns to the genome, as used by SAM
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cigar {
+    entries: Vec<CigarEntry>,
+}
+
+impl Cigar {
+    pub fn new() -> Self {
+        return Cigar::default();
+    }
+
+    // Appends `count` of `op`, merging with the final entry if it is the same operation
+    pub fn push(&mut self, op: CigarOp, count: usize) {
+        if count == 0 {
+            return;
+        }
+
+        match self.entries.last_mut() {
+            Some(last_entry) if last_entry.op == op => last_entry.count += count,
+            _ => self.entries.push(CigarEntry { op, count }),
+        }
+    }
+
+    pub fn entries(&self) -> &[CigarEntry] {
+        return &self.entries;
+    }
+
+    // Number of bases of the read described by the CIGAR
+    pub fn read_length(&self) -> usize {
+        return self
+            .entries
+            .iter()
+            .filter(|entry| entry.op.consumes_read())
+            .map(|entry| entry.count)
+            .sum();
+    }
+
+    // Number of bases of the genome spanned by the CIGAR
+    pub fn genome_length(&self) -> usize {
+        return self
+            .entries
+            .iter()
+            .filter(|entry| entry.op.consumes_genome())
+            .map(|entry| entry.count)
+            .sum();
+    }
+}
+
+impl fmt::Display for Cigar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // SAM uses `*` for an unavailable CIGAR
+        if self.entries.is_empty() {
+            return write!(f, "*");
+        }
+
+        for entry in &self.entries {
+            write!(f, "{}{}", entry.count, entry.op.symbol())?;
+        }
+        return Ok(());
+    }
+}
+
+impl FromStr for Cigar {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let mut cigar = Cigar::new();
+
+        if str == "*" {
+            return Ok(cigar);
+        }
+
+        let mut count = String::new();
+        for char in str.chars() {
+            if char.is_ascii_digit() {
+                count.push(char);
+                continue;
+            }
+
+            let op = match char {
+                'M' => CigarOp::Match,
+                'I' => CigarOp::Insertion,
+                'D' => CigarOp::Deletion,
+                'N' => CigarOp::Skip,
+                'S' => CigarOp::SoftClip,
+                _ => return Err(format!("{char} is not a supported CIGAR operation")),
+            };
+
+            let count = std::mem::take(&mut count)
+                .parse()
+                .map_err(|_| format!("CIGAR operation {char} has no length"))?;
+            cigar.push(op, count);
+        }
+
+        if !count.is_empty() {
+            return Err(format!("CIGAR {str} ends without an operation"));
+        }
+
+        return Ok(cigar);
+    }
+}
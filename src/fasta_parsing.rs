@@ -1,23 +1,49 @@
 use std::fs::File;
-use std::io::{BufReader, BufRead};
+use std::io::{self, BufRead, BufReader};
 
-pub fn read_fasta(file_path: &str) -> String {
-    print!("Loading FASTA in {} to memory", file_path);
+#[derive(Debug, PartialEq)]
+pub struct FastaRecord {
+    // Header up to the first whitespace, as used for reference names in SAM
+    pub name: String,
+    pub sequence: String,
+}
 
-    let file = File::open(file_path).expect("FASTA failed to open");
+impl FastaRecord {
+    pub fn new(name: &str, sequence: &str) -> Self {
+        return FastaRecord {
+            name: String::from(name),
+            sequence: String::from(sequence),
+        };
+    }
+}
+
+// Reads every record of a FASTA file, failing if the file can't be read or has no header
+pub fn read_fasta(file_path: &str) -> io::Result<Vec<FastaRecord>> {
+    let file = File::open(file_path)?;
     let reader = BufReader::new(file);
 
-    let lines: Vec<String> = reader
-        .lines() // Get an iterator over lines
-        .filter_map(Result::ok) // Ignore any errors
-        .map(|line| line.to_ascii_uppercase())
-        .filter(|line| line.chars().next().unwrap() != '>')
-        .collect();
-    
-    let genome = lines.join("");
+    let mut records: Vec<FastaRecord> = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+
+        // Each record starts with a header line `>name description`
+        if let Some(header) = line.strip_prefix('>') {
+            let name = header.split_whitespace().next().unwrap_or("");
+            records.push(FastaRecord::new(name, ""));
+            continue;
+        }
 
-    println!(" [DONE]");
-    println!("Genome: {}...", &genome[..30]);
+        let Some(record) = records.last_mut() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{file_path}: FASTA sequence found before the first header"),
+            ));
+        };
+        record
+            .sequence
+            .push_str(&line.trim_end().to_ascii_uppercase());
+    }
 
-    return genome;
-}
\ No newline at end of file
+    return Ok(records);
+}
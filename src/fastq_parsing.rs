@@ -1,17 +1,419 @@
+use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, BufRead};
-
-pub fn read_fastq(file_path: &str) -> impl Iterator<Item = String> {
-    let file = File::open(file_path).expect("FASTQ failed to open");
-    let reader = BufReader::new(file);
-
-    // TODO: Make more robust - assumes that the 1st, 5th, 9th, 13th lines are the reads 
-    let reads = reader
-        .lines() // Get an iterator over lines
-        .filter_map(Result::ok) // Ignore any errors
-        .skip(1)
-        .step_by(4)
-        .filter(|line| line.chars().all(|c| matches!(c, 'A' | 'T' | 'C' | 'G')));
-
-    return reads;
-}
\ No newline at end of file
+use std::io::{self, BufRead, BufReader, Lines};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FastqRecord {
+    // Header up to the first whitespace
+    pub name: String,
+    // Rest of the header, if any
+    pub comment: Option<String>,
+    pub seq: String,
+    // Phred+33 encoded, one character per base of `seq`
+    pub qual: String,
+}
+
+#[derive(Debug)]
+pub enum FastqErrorKind {
+    Io(io::Error),
+    MissingHeader,
+    MissingSeparator,
+    // The file ended part way through a record
+    Truncated,
+    LengthMismatch {
+        seq_length: usize,
+        qual_length: usize,
+    },
+    // One file of a pair (or an interleaved file) has a read without its mate
+    MissingMate,
+    MateNameMismatch {
+        first_name: String,
+        second_name: String,
+    },
+}
+
+#[derive(Debug)]
+pub struct FastqError {
+    // 1-based line of the file where the problem was found. For `Truncated`, the missing line
+    pub line_number: usize,
+    pub kind: FastqErrorKind,
+}
+
+impl fmt::Display for FastqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FASTQ line {}: ", self.line_number)?;
+
+        match &self.kind {
+            FastqErrorKind::Io(error) => return write!(f, "{error}"),
+            FastqErrorKind::MissingHeader => {
+                return write!(f, "expected a header starting with '@'")
+            }
+            FastqErrorKind::MissingSeparator => {
+                return write!(f, "expected a separator starting with '+'")
+            }
+            FastqErrorKind::Truncated => return write!(f, "file ends part way through a record"),
+            FastqErrorKind::LengthMismatch {
+                seq_length,
+                qual_length,
+            } => {
+                return write!(
+                    f,
+                    "quality has {qual_length} characters but the sequence has {seq_length} bases"
+                )
+            }
+            FastqErrorKind::MissingMate => return write!(f, "read has no mate"),
+            FastqErrorKind::MateNameMismatch {
+                first_name,
+                second_name,
+            } => {
+                return write!(
+                    f,
+                    "mates have different names, {first_name} and {second_name}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for FastqError {}
+
+// Iterator over the records of a 4-line FASTQ file, stopping after the first error
+pub struct FastqRecords<R: BufRead> {
+    lines: Lines<R>,
+    line_number: usize,
+    failed: bool,
+}
+
+impl<R: BufRead> FastqRecords<R> {
+    pub fn new(reader: R) -> Self {
+        return FastqRecords {
+            lines: reader.lines(),
+            line_number: 0,
+            failed: false,
+        };
+    }
+
+    // Reads the next line, or returns `None` at the end of the file
+    fn next_line(&mut self) -> Option<Result<String, FastqError>> {
+        let line = self.lines.next()?;
+        self.line_number += 1;
+
+        return Some(line.map_err(|error| self.error(FastqErrorKind::Io(error))));
+    }
+
+    // Reads the next line of a record which has already started
+    fn record_line(&mut self) -> Result<String, FastqError> {
+        return self.next_line().unwrap_or_else(|| {
+            return Err(FastqError {
+                line_number: self.line_number + 1,
+                kind: FastqErrorKind::Truncated,
+            });
+        });
+    }
+
+    fn error(&self, kind: FastqErrorKind) -> FastqError {
+        return FastqError {
+            line_number: self.line_number,
+            kind,
+        };
+    }
+
+    fn read_record(&mut self, header: String) -> Result<FastqRecord, FastqError> {
+        let Some(header) = header.strip_prefix('@') else {
+            return Err(self.error(FastqErrorKind::MissingHeader));
+        };
+
+        let (name, comment) = match header.split_once(char::is_whitespace) {
+            Some((name, comment)) => (name, Some(comment.trim().to_string())),
+            None => (header, None),
+        };
+
+        let seq = self.record_line()?.trim_end().to_string();
+
+        if !self.record_line()?.starts_with('+') {
+            return Err(self.error(FastqErrorKind::MissingSeparator));
+        }
+
+        let qual = self.record_line()?.trim_end().to_string();
+
+        if seq.len() != qual.len() {
+            return Err(self.error(FastqErrorKind::LengthMismatch {
+                seq_length: seq.len(),
+                qual_length: qual.len(),
+            }));
+        }
+
+        return Ok(FastqRecord {
+            name: name.to_string(),
+            comment,
+            seq,
+            qual,
+        });
+    }
+}
+
+impl<R: BufRead> Iterator for FastqRecords<R> {
+    type Item = Result<FastqRecord, FastqError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        // Blank lines between records (eg at the end of the file) are ignored
+        let header = loop {
+            match self.next_line()? {
+                Ok(line) if line.trim().is_empty() => continue,
+                line => break line,
+            }
+        };
+
+        let record = header.and_then(|header| self.read_record(header));
+        self.failed = record.is_err();
+
+        return Some(record);
+    }
+}
+
+/*
+ * Iterator over the mates of paired-end reads, stopping after the first error. The mates either
+ * come from separate R1 and R2 files in the same order, or are interleaved in a single file with
+ * each R1 record followed by its R2 record.
+ *
+ * Errors with the second mate (eg a missing mate) give the line number in the file containing it.
+ * The /1 and /2 suffixes of mate names are removed, since SAM requires mates to share a name.
+ */
+pub struct PairedFastqRecords<R: BufRead> {
+    first_records: FastqRecords<R>,
+    // `None` when the mates are interleaved in `first_records`
+    second_records: Option<FastqRecords<R>>,
+    failed: bool,
+}
+
+impl<R: BufRead> PairedFastqRecords<R> {
+    pub fn new(first_reader: R, second_reader: R) -> Self {
+        return PairedFastqRecords {
+            first_records: FastqRecords::new(first_reader),
+            second_records: Some(FastqRecords::new(second_reader)),
+            failed: false,
+        };
+    }
+
+    pub fn interleaved(reader: R) -> Self {
+        return PairedFastqRecords {
+            first_records: FastqRecords::new(reader),
+            second_records: None,
+            failed: false,
+        };
+    }
+
+    fn next_pair(&mut self) -> Option<Result<(FastqRecord, FastqRecord), FastqError>> {
+        let first = self.first_records.next();
+
+        let second_records = self
+            .second_records
+            .as_mut()
+            .unwrap_or(&mut self.first_records);
+
+        let (mut first, mut second) = match (first, second_records.next()) {
+            (None, None) => return None,
+            (Some(Err(error)), _) | (_, Some(Err(error))) => return Some(Err(error)),
+            (Some(Ok(first)), Some(Ok(second))) => (first, second),
+            /*
+             * One file is shorter. Either way the error is reported in the R2 file, at the read
+             * without a mate or where the missing read should be
+             */
+            (None, Some(Ok(_))) | (Some(Ok(_)), None) => {
+                return Some(Err(second_records.error(FastqErrorKind::MissingMate)));
+            }
+        };
+
+        first.name = mate_name(&first.name).to_string();
+        second.name = mate_name(&second.name).to_string();
+
+        if first.name != second.name {
+            return Some(Err(second_records.error(
+                FastqErrorKind::MateNameMismatch {
+                    first_name: first.name,
+                    second_name: second.name,
+                },
+            )));
+        }
+
+        return Some(Ok((first, second)));
+    }
+}
+
+impl<R: BufRead> Iterator for PairedFastqRecords<R> {
+    type Item = Result<(FastqRecord, FastqRecord), FastqError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let pair = self.next_pair()?;
+        self.failed = pair.is_err();
+
+        return Some(pair);
+    }
+}
+
+// Name of a mate without the /1 or /2 suffix used by older Illumina pipelines
+fn mate_name(name: &str) -> &str {
+    return name
+        .strip_suffix("/1")
+        .or_else(|| name.strip_suffix("/2"))
+        .unwrap_or(name);
+}
+
+pub fn read_fastq(file_path: &str) -> io::Result<FastqRecords<BufReader<File>>> {
+    let file = File::open(file_path)?;
+    return Ok(FastqRecords::new(BufReader::new(file)));
+}
+
+// Reads paired-end reads from separate R1 and R2 files
+pub fn read_paired_fastq(
+    first_file_path: &str,
+    second_file_path: &str,
+) -> io::Result<PairedFastqRecords<BufReader<File>>> {
+    let first_file = File::open(first_file_path)?;
+    let second_file = File::open(second_file_path)?;
+    return Ok(PairedFastqRecords::new(
+        BufReader::new(first_file),
+        BufReader::new(second_file),
+    ));
+}
+
+// Reads paired-end reads interleaved in a single file
+pub fn read_interleaved_fastq(file_path: &str) -> io::Result<PairedFastqRecords<BufReader<File>>> {
+    let file = File::open(file_path)?;
+    return Ok(PairedFastqRecords::interleaved(BufReader::new(file)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(fastq: &str) -> Vec<Result<FastqRecord, FastqError>> {
+        return FastqRecords::new(fastq.as_bytes()).collect();
+    }
+
+    #[test]
+    fn records() {
+        let records = parse("@read1 length=4\nACGT\n+\nIIII\n@read2\nGGN\n+read2\n#!I\n\n");
+
+        assert_eq!(
+            records.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            vec![
+                FastqRecord {
+                    name: String::from("read1"),
+                    comment: Some(String::from("length=4")),
+                    seq: String::from("ACGT"),
+                    qual: String::from("IIII"),
+                },
+                FastqRecord {
+                    name: String::from("read2"),
+                    comment: None,
+                    seq: String::from("GGN"),
+                    qual: String::from("#!I"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_records() {
+        let error_line = |fastq: &str| {
+            let records = parse(fastq);
+            // Parsing stops at the first error
+            assert!(records.last().unwrap().is_err());
+            return records
+                .into_iter()
+                .find_map(Result::err)
+                .unwrap()
+                .line_number;
+        };
+
+        assert_eq!(
+            error_line("@read1\nACGT\n+\nIIII\nread2\nACGT\n+\nIIII\n"),
+            5
+        );
+        assert_eq!(error_line("@read1\nACGT\n-\nIIII\n"), 3);
+        assert_eq!(
+            error_line("@read1\nACGT\n+\nIII\n@read2\nACGT\n+\nIIII\n"),
+            4
+        );
+        // The quality line is missing
+        assert_eq!(error_line("@read1\nACGT\n+\n"), 4);
+    }
+
+    #[test]
+    fn paired_records() {
+        let names = |pairs: Vec<Result<(FastqRecord, FastqRecord), FastqError>>| {
+            return pairs
+                .into_iter()
+                .map(|pair| {
+                    let (first, second) = pair.unwrap();
+                    return (first.name, first.seq, second.name, second.seq);
+                })
+                .collect::<Vec<_>>();
+        };
+        let expected = vec![
+            (
+                String::from("pair1"),
+                String::from("ACGT"),
+                String::from("pair1"),
+                String::from("TTGA"),
+            ),
+            (
+                String::from("pair2"),
+                String::from("GGCC"),
+                String::from("pair2"),
+                String::from("AACC"),
+            ),
+        ];
+
+        let first = "@pair1/1\nACGT\n+\nIIII\n@pair2/1\nGGCC\n+\nIIII\n";
+        let second = "@pair1/2\nTTGA\n+\nIIII\n@pair2/2\nAACC\n+\nIIII\n";
+        let pairs = PairedFastqRecords::new(first.as_bytes(), second.as_bytes()).collect();
+        assert_eq!(names(pairs), expected);
+
+        let interleaved = "@pair1 1:N\nACGT\n+\nIIII\n@pair1 2:N\nTTGA\n+\nIIII\n\
+                           @pair2 1:N\nGGCC\n+\nIIII\n@pair2 2:N\nAACC\n+\nIIII\n";
+        let pairs = PairedFastqRecords::interleaved(interleaved.as_bytes()).collect();
+        assert_eq!(names(pairs), expected);
+    }
+
+    #[test]
+    fn malformed_paired_records() {
+        let error = |pairs: PairedFastqRecords<&[u8]>| {
+            let pairs: Vec<_> = pairs.collect();
+            // Parsing stops at the first error
+            let error = pairs.into_iter().last().unwrap().unwrap_err();
+            return (error.line_number, error.kind);
+        };
+
+        // The R2 file is missing the mate of pair2
+        let first = "@pair1/1\nACGT\n+\nIIII\n@pair2/1\nGGCC\n+\nIIII\n";
+        let second = "@pair1/2\nTTGA\n+\nIIII\n";
+        assert!(matches!(
+            error(PairedFastqRecords::new(first.as_bytes(), second.as_bytes())),
+            (4, FastqErrorKind::MissingMate)
+        ));
+
+        // An odd number of interleaved records
+        let interleaved =
+            "@pair1/1\nACGT\n+\nIIII\n@pair1/2\nTTGA\n+\nIIII\n@pair2/1\nGGCC\n+\nIIII\n";
+        assert!(matches!(
+            error(PairedFastqRecords::interleaved(interleaved.as_bytes())),
+            (12, FastqErrorKind::MissingMate)
+        ));
+
+        let second = "@pair1/2\nTTGA\n+\nIIII\n@pair3/2\nAACC\n+\nIIII\n";
+        assert!(matches!(
+            error(PairedFastqRecords::new(first.as_bytes(), second.as_bytes())),
+            (8, FastqErrorKind::MateNameMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+use std::mem::size_of;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+use bytemuck::Pod;
+use memmap2::Mmap;
+
+/*
+ * Array of fixed size elements, either built in memory or read in place from a memory mapped
+ * index file. Either way it's used as a slice, so the FM index doesn't need to know which.
+ */
+pub struct FlatArray<T: Pod> {
+    storage: Storage<T>,
+}
+
+enum Storage<T: 'static> {
+    Owned(Vec<T>),
+    /*
+     * Points into `mmap`, which is never unmapped while the slice is held. The file must not be
+     * modified while it's mapped.
+     */
+    Mapped {
+        elements: &'static [T],
+        _mmap: Arc<Mmap>,
+    },
+}
+
+impl<T: Pod> FlatArray<T> {
+    /*
+     * Uses the bytes in `byte_range` of `mmap` as an array, without copying them. Returns `None`
+     * if the range is outside the file, or isn't aligned for `T`.
+     */
+    pub fn mapped(mmap: &Arc<Mmap>, byte_range: Range<usize>) -> Option<Self> {
+        let bytes = mmap.get(byte_range)?;
+        let elements: &[T] = bytemuck::try_cast_slice(bytes).ok()?;
+
+        // Safe as the mapping lives at least as long as `_mmap`, which is stored alongside
+        let elements: &'static [T] =
+            unsafe { std::slice::from_raw_parts(elements.as_ptr(), elements.len()) };
+
+        return Some(FlatArray {
+            storage: Storage::Mapped {
+                elements,
+                _mmap: Arc::clone(mmap),
+            },
+        });
+    }
+
+    // Whether the array is read from a memory mapped file rather than held in memory
+    pub fn is_mapped(&self) -> bool {
+        return matches!(self.storage, Storage::Mapped { .. });
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        return self.len() * size_of::<T>();
+    }
+}
+
+impl<T: Pod> From<Vec<T>> for FlatArray<T> {
+    fn from(elements: Vec<T>) -> Self {
+        return FlatArray {
+            storage: Storage::Owned(elements),
+        };
+    }
+}
+
+impl<T: Pod> Default for FlatArray<T> {
+    fn default() -> Self {
+        return FlatArray::from(Vec::new());
+    }
+}
+
+impl<T: Pod> Deref for FlatArray<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match &self.storage {
+            Storage::Owned(elements) => return elements,
+            Storage::Mapped { elements, .. } => return elements,
+        }
+    }
+}
+
+impl<T: Pod + fmt::Debug> fmt::Debug for FlatArray<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f.debug_list().entries(self.iter()).finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn mapped() {
+        let path =
+            std::env::temp_dir().join(format!("read_mapping_flat_{}.bin", std::process::id()));
+        let words: Vec<usize> = vec![0, 3, usize::MAX, 42];
+        std::fs::write(&path, bytemuck::cast_slice::<usize, u8>(&words)).unwrap();
+
+        let mmap = Arc::new(unsafe { Mmap::map(&File::open(&path).unwrap()).unwrap() });
+        std::fs::remove_file(&path).unwrap();
+
+        let array: FlatArray<usize> = FlatArray::mapped(&mmap, 8..32).unwrap();
+        assert!(array.is_mapped());
+        assert_eq!(*array, words[1..]);
+        assert_eq!(array.size_in_bytes(), 24);
+
+        // Misaligned, and past the end of the file
+        assert!(FlatArray::<usize>::mapped(&mmap, 4..12).is_none());
+        assert!(FlatArray::<usize>::mapped(&mmap, 24..40).is_none());
+
+        let bytes: FlatArray<u8> = FlatArray::mapped(&mmap, 3..5).unwrap();
+        assert_eq!(bytes.len(), 2);
+    }
+}
@@ -1,20 +1,77 @@
-use bincode::{Encode, Decode};
 use suffix_array::SuffixArray;
 
-use std::collections::HashMap;
 use std::fmt::Debug;
+use std::io::{self, Write};
 
+use crate::flat_array::FlatArray;
+use crate::index_file::{BwtStorage, IndexFileError, IndexReader, IndexWriter, SamplingSteps};
 use crate::nucleotide_stratified::NucStratified;
+use crate::packed_bwt::PackedBwt;
 use crate::run_length_encoding::RunLengthEncodedString;
+use crate::succinct::{PackedArray, SparseBitVector};
 
 type SuffixArrayIndex = usize;
 
-#[derive(Debug, Encode, Decode)]
+/*
+ * Symbol in the indexed string which never matches a read, eg between contigs. Lookups containing
+ * it find nothing, and extension stops as soon as it reaches it.
+ */
+pub const NON_NUCLEOTIDE: char = 'N';
+
+// Scores used when extending matches with mismatches
+pub const MATCH_SCORE: isize = 1;
+pub const MISMATCH_PENALTY: isize = 4;
+
+#[derive(Debug, PartialEq)]
+pub struct ExtensionResult {
+    // Number of characters of the extension covered by the match
+    pub match_length: usize,
+    // Indices into the extension of each mismatch within the match
+    pub mismatch_positions: Vec<usize>,
+}
+
+/*
+ * Bytes used by each part of an FM index. For an index loaded from a file, this is the size of
+ * the parts of the file which are memory mapped.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FMIndexMemoryUsage {
+    pub bwt: usize,
+    pub rank_checkpoints: usize,
+    pub suffix_array: usize,
+    pub inverse_suffix_array: usize,
+}
+
+impl FMIndexMemoryUsage {
+    pub fn total(&self) -> usize {
+        return self.bwt + self.rank_checkpoints + self.suffix_array + self.inverse_suffix_array;
+    }
+}
+
+// Nucleotides with a rank checkpoint, in the order they're saved
+const RANKED_NUCLEOTIDES: [char; 5] = ['A', 'C', 'G', NON_NUCLEOTIDE, 'T'];
+
+// There's only one per FMIndex, so boxing the larger variant would only add an indirection
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+enum Bwt {
+    RunLength {
+        compressed_bwt: RunLengthEncodedString,
+        ranks: NucStratified<FlatArray<usize>>,
+    },
+    // Nucleotide counts are interleaved with the bases, so there are no separate rank checkpoints
+    Packed(PackedBwt),
+}
+
+#[derive(Debug)]
 pub struct FMIndex {
-    compressed_bwt: RunLengthEncodedString,
-    sampled_suffix_array: HashMap<usize, usize>,
+    bwt: Bwt,
+    // Marks the rows of the BW matrix whose suffix array entry is sampled...
+    sampled_suffix_array_rows: SparseBitVector,
+    // ...and gives the entry of each sampled row in order, divided by the sampling step
+    sampled_suffix_array: PackedArray,
+    sampled_inverse_suffix_array: PackedArray,
     first_bwt_column_index: NucStratified<usize>,
-    ranks: NucStratified<Vec<usize>>,
     rank_sampling_step_size: usize,
     suffix_array_sampling_step_size: usize,
     seq_len: usize,
@@ -26,35 +83,42 @@ impl FMIndex {
         str: &str,
         suffix_array_sampling_step_size: usize,
         rank_sampling_step_size: usize,
+    ) -> Self {
+        return FMIndex::with_bwt_storage(
+            str,
+            suffix_array_sampling_step_size,
+            rank_sampling_step_size,
+            BwtStorage::RunLength,
+        );
+    }
+
+    pub fn with_bwt_storage(
+        str: &str,
+        suffix_array_sampling_step_size: usize,
+        rank_sampling_step_size: usize,
+        bwt_storage: BwtStorage,
     ) -> Self {
         let suffix_array = construct_suffix_array(str);
 
         let bwt = construct_bwt(str, &suffix_array);
-        let compressed_bwt = RunLengthEncodedString::new(&bwt, rank_sampling_step_size);
+
+        let sampled_inverse_suffix_array =
+            sample_inverse_suffix_array(&suffix_array, suffix_array_sampling_step_size);
 
         /*
          * Constructing the BWT requires the full suffix array
          * Since 'sample_suffix_array' takes ownership of the suffix array we can only take a sample
          * after the BWT has been contructed
          */
-        let sampled_suffix_array =
+        let (sampled_suffix_array_rows, sampled_suffix_array) =
             sample_suffix_array(suffix_array, suffix_array_sampling_step_size);
 
         let mut counts: NucStratified<usize> = NucStratified::default();
-        let mut ranks: NucStratified<Vec<usize>> = NucStratified::default();
-
-        for (i, nuc) in bwt.chars().enumerate() {
+        for nuc in bwt.chars() {
             if nuc != '$' {
                 // += operator does not do automatic dereferencing so have to do it ourselves
                 *counts.get_mut(nuc) += 1;
             }
-
-            if i % rank_sampling_step_size == 0 {
-                for nuc_key in ['A', 'C', 'G', 'T'] {
-                    let current_nuc_count = *counts.get(nuc_key);
-                    ranks.get_mut(nuc_key).push(current_nuc_count);
-                }
-            }
         }
 
         /*
@@ -65,17 +129,160 @@ impl FMIndex {
          */
         let first_bwt_column_index = get_first_bwt_column_index(&counts);
 
+        let bwt = match bwt_storage {
+            BwtStorage::RunLength => Bwt::RunLength {
+                compressed_bwt: RunLengthEncodedString::new(&bwt, rank_sampling_step_size),
+                ranks: sample_ranks(&bwt, rank_sampling_step_size),
+            },
+            BwtStorage::Packed => Bwt::Packed(PackedBwt::new(&bwt, rank_sampling_step_size)),
+        };
+
         FMIndex {
-            compressed_bwt,
+            bwt,
+            sampled_suffix_array_rows,
             sampled_suffix_array,
+            sampled_inverse_suffix_array,
             first_bwt_column_index,
-            ranks,
             rank_sampling_step_size,
             suffix_array_sampling_step_size,
             seq_len: str.len(),
         }
     }
 
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut IndexWriter<W>) -> io::Result<()> {
+        writer.write_word(self.seq_len)?;
+        writer.write_word(self.suffix_array_sampling_step_size)?;
+        writer.write_word(self.rank_sampling_step_size)?;
+
+        self.sampled_suffix_array_rows.write_to(writer)?;
+        self.sampled_suffix_array.write_to(writer)?;
+        self.sampled_inverse_suffix_array.write_to(writer)?;
+        for nucleotide in RANKED_NUCLEOTIDES {
+            writer.write_word(*self.first_bwt_column_index.get(nucleotide))?;
+        }
+
+        match &self.bwt {
+            Bwt::RunLength {
+                compressed_bwt,
+                ranks,
+            } => {
+                writer.write_word(BwtStorage::RunLength as usize)?;
+                compressed_bwt.write_to(writer)?;
+                for nucleotide in RANKED_NUCLEOTIDES {
+                    writer.write_array(ranks.get(nucleotide))?;
+                }
+            }
+            Bwt::Packed(packed_bwt) => {
+                writer.write_word(BwtStorage::Packed as usize)?;
+                packed_bwt.write_to(writer)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Reads an index written by `write_to`, using its arrays in place
+    pub(crate) fn read_from(reader: &mut IndexReader) -> Result<Self, IndexFileError> {
+        let seq_len = reader.read_word()?;
+        let suffix_array_sampling_step_size = reader.read_word()?;
+        let rank_sampling_step_size = reader.read_word()?;
+        if suffix_array_sampling_step_size == 0 || rank_sampling_step_size == 0 {
+            return Err(IndexFileError::Corrupt("sampling step of 0".to_string()));
+        }
+
+        let sampled_suffix_array_rows = SparseBitVector::read_from(reader)?;
+        let sampled_suffix_array = PackedArray::read_from(reader)?;
+        let sampled_inverse_suffix_array = PackedArray::read_from(reader)?;
+        let mut first_bwt_column_index = NucStratified::default();
+        for nucleotide in RANKED_NUCLEOTIDES {
+            *first_bwt_column_index.get_mut(nucleotide) = reader.read_word()?;
+        }
+
+        let bwt = match reader.read_word()? {
+            storage if storage == BwtStorage::RunLength as usize => {
+                read_run_length_bwt(reader, seq_len, rank_sampling_step_size)?
+            }
+            storage if storage == BwtStorage::Packed as usize => {
+                let packed_bwt = PackedBwt::read_from(reader, seq_len, rank_sampling_step_size)?;
+                Bwt::Packed(packed_bwt)
+            }
+            storage => {
+                return Err(IndexFileError::Corrupt(format!(
+                    "unknown BWT storage {storage}"
+                )))
+            }
+        };
+
+        let fm_index = FMIndex {
+            bwt,
+            sampled_suffix_array_rows,
+            sampled_suffix_array,
+            sampled_inverse_suffix_array,
+            first_bwt_column_index,
+            rank_sampling_step_size,
+            suffix_array_sampling_step_size,
+            seq_len,
+        };
+
+        let suffix_array_sample_count = seq_len.div_ceil(suffix_array_sampling_step_size);
+        if fm_index.sampled_suffix_array_rows.len() != seq_len
+            || fm_index.sampled_suffix_array_rows.count_ones() != suffix_array_sample_count
+            || fm_index.sampled_suffix_array.len() != suffix_array_sample_count
+            || fm_index.sampled_inverse_suffix_array.len() != suffix_array_sample_count
+            || !first_bwt_column_index_consistent(&fm_index.first_bwt_column_index, seq_len)
+        {
+            return Err(IndexFileError::Corrupt("inconsistent FM index".to_string()));
+        }
+
+        return Ok(fm_index);
+    }
+
+    pub fn sampling_steps(&self) -> SamplingSteps {
+        return SamplingSteps {
+            suffix_array: self.suffix_array_sampling_step_size,
+            rank: self.rank_sampling_step_size,
+        };
+    }
+
+    pub fn bwt_storage(&self) -> BwtStorage {
+        match self.bwt {
+            Bwt::RunLength { .. } => return BwtStorage::RunLength,
+            Bwt::Packed(_) => return BwtStorage::Packed,
+        }
+    }
+
+    pub fn memory_usage(&self) -> FMIndexMemoryUsage {
+        let (bwt, rank_checkpoints) = match &self.bwt {
+            Bwt::RunLength {
+                compressed_bwt,
+                ranks,
+            } => (
+                compressed_bwt.size_in_bytes(),
+                RANKED_NUCLEOTIDES
+                    .iter()
+                    .map(|&nucleotide| ranks.get(nucleotide).size_in_bytes())
+                    .sum(),
+            ),
+            Bwt::Packed(packed_bwt) => (
+                packed_bwt.bases_size_in_bytes(),
+                packed_bwt.counts_size_in_bytes(),
+            ),
+        };
+
+        return FMIndexMemoryUsage {
+            bwt,
+            rank_checkpoints,
+            suffix_array: self.sampled_suffix_array_rows.size_in_bytes()
+                + self.sampled_suffix_array.size_in_bytes(),
+            inverse_suffix_array: self.sampled_inverse_suffix_array.size_in_bytes(),
+        };
+    }
+
+    // Length of the indexed string, including the sentinel
+    pub fn seq_len(&self) -> usize {
+        return self.seq_len;
+    }
+
     pub fn lookup(&self, target_str: &str) -> impl Iterator<Item = SuffixArrayIndex> {
         // Inclusive
         let mut bottom = 0;
@@ -86,6 +293,11 @@ impl FMIndex {
         // Given a top and bottom, each iteration should extend by one charecter and find new top and bottom in first column of bwt table
         // Once no charecters left, lookup range in SA
         for nucleotide in target_str.chars().rev() {
+            // Reads can never match across the end of a contig
+            if !matches!(nucleotide, 'A' | 'C' | 'G' | 'T') {
+                return 0..0; // Empty range
+            }
+
             /*
              * The rank of the first `nucleotide`` in the [bottom, top) range is one larger
              * than the largest rank seen before the range.
@@ -113,16 +325,23 @@ impl FMIndex {
 
     // Should panic if gets wrong index
     fn get_rank_for_index(&self, nucleotide: char, index: usize) -> usize {
+        let (compressed_bwt, ranks) = match &self.bwt {
+            Bwt::RunLength {
+                compressed_bwt,
+                ranks,
+            } => (compressed_bwt, ranks),
+            Bwt::Packed(packed_bwt) => return packed_bwt.rank(nucleotide, index),
+        };
+
         let checkpoint_index = index / self.rank_sampling_step_size;
 
-        let rank_checkpoint = self
-            .ranks
+        let rank_checkpoint = ranks
             .get(nucleotide)
             .get(checkpoint_index)
             .expect("coarse_rank_start_index not in range");
 
         // TODO: Fix multipliciation in argument list
-        let missing_nuc_instances = self.compressed_bwt.count_matches_from_checkpoint(
+        let missing_nuc_instances = compressed_bwt.count_matches_from_checkpoint(
             nucleotide,
             checkpoint_index * self.rank_sampling_step_size,
             index,
@@ -131,6 +350,16 @@ impl FMIndex {
         return rank_checkpoint + missing_nuc_instances;
     }
 
+    // Character of the BWT at `index`, ie the character preceding that row's suffix
+    fn get_bwt_char(&self, index: usize) -> char {
+        match &self.bwt {
+            Bwt::RunLength { compressed_bwt, .. } => {
+                return compressed_bwt.get_char_from_position(index)
+            }
+            Bwt::Packed(packed_bwt) => return packed_bwt.get_char_from_position(index),
+        }
+    }
+
     pub fn get_genome_position(&self, target_index: SuffixArrayIndex) -> usize {
         let mut current_index = target_index;
 
@@ -138,7 +367,7 @@ impl FMIndex {
          * General idea is to `walk back` through the string by repeatedly applying the LF mapping until we
          * find a suffix with a sampled SA entry.
          *
-         * `self.sampled_suffix_array` samples the SA every `suffix_array_sampling_step_size` entries
+         * `self.sampled_suffix_array_rows` samples the SA every `suffix_array_sampling_step_size` entries
          * by entry index - not the index of the suffix in the original string! This ensures that it takes
          * at most `suffix_array_sampling_step_size` steps to find a sampled SA entry.
          */
@@ -153,7 +382,9 @@ impl FMIndex {
              * so the original index of the suffix [target_index:] is
              * sampled entry + the numbers of chars we walked back.
              */
-            if let Some(suffix_array_entry) = self.sampled_suffix_array.get(&current_index) {
+            if let Some(sample_index) = self.sampled_suffix_array_rows.rank_if_set(current_index) {
+                let suffix_array_entry = self.sampled_suffix_array.get(sample_index)
+                    * self.suffix_array_sampling_step_size;
                 return suffix_array_entry + steps;
             }
 
@@ -166,7 +397,7 @@ impl FMIndex {
              * 2. Find the index of the row in the BW matrix that starts with the preceding
              * charecter we just found using the LF mapping
              */
-            let nucleotide = self.compressed_bwt.get_char_from_position(current_index);
+            let nucleotide = self.get_bwt_char(current_index);
             let rank = self.get_rank_for_index(nucleotide, current_index);
 
             current_index = self.last_to_first_mapping(nucleotide, rank);
@@ -174,17 +405,92 @@ impl FMIndex {
         panic!("DIDN'T FIND SA ENTRY WHEN SHOULD HAVE")
     }
 
-    // TODO: Could also return genome_pos of extension start
-    // Match index is the row of the bwt where the match begins
-    pub fn count_extension_matches(&self, match_index: SuffixArrayIndex, extension: &str) -> usize {
+    // Returns str[start..end), which must not include the sentinel
+    pub fn extract(&self, start: usize, end: usize) -> String {
+        assert!(start <= end && end < self.seq_len);
+
+        /*
+         * Find the closest sampled suffix starting at or after `end`. If there isn't one, use the
+         * suffix consisting of just the sentinel, which is always the first row of the BW matrix.
+         */
+        let checkpoint = end.div_ceil(self.suffix_array_sampling_step_size);
+        let (mut current_position, mut current_index) =
+            if checkpoint < self.sampled_inverse_suffix_array.len() {
+                (
+                    checkpoint * self.suffix_array_sampling_step_size,
+                    self.sampled_inverse_suffix_array.get(checkpoint),
+                )
+            } else {
+                (self.seq_len - 1, 0)
+            };
+
+        /*
+         * Walk back through the string one character at a time using the LF mapping. The BWT
+         * gives the character preceding the current suffix, ie str[current_position - 1].
+         */
+        let mut reversed_chars = Vec::with_capacity(end - start);
+        while current_position > start {
+            let nucleotide = self.get_bwt_char(current_index);
+            current_position -= 1;
+
+            if current_position < end {
+                reversed_chars.push(nucleotide);
+            }
+
+            let rank = self.get_rank_for_index(nucleotide, current_index);
+            current_index = self.last_to_first_mapping(nucleotide, rank);
+        }
+
+        return reversed_chars.into_iter().rev().collect();
+    }
+
+    /*
+     * Extends a match backwards through the genome, comparing each preceding character against
+     * `extension`. Match index is the row of the bwt where the match begins.
+     *
+     * Mismatches are tolerated until `max_mismatches` is exceeded, or the score drops more than
+     * `x_drop` below the best score seen so far (X-drop). The extension is then trimmed back to the
+     * best scoring prefix, so it never ends on a mismatch.
+     */
+    pub fn count_extension_matches(
+        &self,
+        match_index: SuffixArrayIndex,
+        extension: &str,
+        max_mismatches: usize,
+        x_drop: usize,
+    ) -> ExtensionResult {
         let mut current_index = match_index;
 
-        for (match_count, read_nuc) in extension.chars().enumerate() {
+        let mut mismatch_positions = Vec::new();
+        let mut score: isize = 0;
+        let mut best_score: isize = 0;
+        let mut best_match_length = 0;
+
+        for (extension_index, read_nuc) in extension.chars().enumerate() {
             // Read the previous character in the string
-            let nucleotide = self.compressed_bwt.get_char_from_position(current_index);
+            let nucleotide = self.get_bwt_char(current_index);
+
+            // Reached the start of the genome or contig so can't extend any further
+            if nucleotide == '$' || nucleotide == NON_NUCLEOTIDE {
+                break;
+            }
+
+            // An N in the read never equals the genome here, so always counts as a mismatch
+            if nucleotide == read_nuc {
+                score += MATCH_SCORE;
+            } else {
+                if mismatch_positions.len() == max_mismatches {
+                    break;
+                }
+                mismatch_positions.push(extension_index);
+                score -= MISMATCH_PENALTY;
+            }
 
-            if nucleotide != read_nuc {
-                return match_count;
+            if score > best_score {
+                best_score = score;
+                best_match_length = extension_index + 1;
+            } else if best_score - score > x_drop as isize {
+                break;
             }
 
             // Find the BWT row assoicated with the preceding character
@@ -192,8 +498,13 @@ impl FMIndex {
             current_index = self.last_to_first_mapping(nucleotide, rank);
         }
 
-        // Matched whole extension
-        return extension.len();
+        // Mismatches after the best scoring prefix are not part of the match
+        mismatch_positions.retain(|&position| position < best_match_length);
+
+        return ExtensionResult {
+            match_length: best_match_length,
+            mismatch_positions,
+        };
     }
 
     /*
@@ -229,7 +540,7 @@ fn construct_suffix_array(str: &str) -> Vec<usize> {
      * 
      * Stripping the final character as the crate adds its own sentinel
      */ 
-    let suffix_array = SuffixArray::new(&str[..str.len() - 1].as_bytes());
+    let suffix_array = SuffixArray::new(&str.as_bytes()[..str.len() - 1]);
 
     return suffix_array
         .into_parts()
@@ -243,23 +554,143 @@ fn construct_suffix_array(str: &str) -> Vec<usize> {
 fn sample_suffix_array(
     suffix_array: Vec<usize>,
     suffix_array_sampling_step_size: usize,
-) -> HashMap<usize, usize> {
+) -> (SparseBitVector, PackedArray) {
     /*
      * To ensure O(1) lookup of the SA entries which have not been sampled, sample every
      * `suffix_array_sampling_step_size`th entry by value.
      *
      * In `get_genome_position` we traverse back one suffix at a time in their lexicographical ordering,
-     * so using this hashmap we will find a sampled SA entry in at most `suffix_array_sampling_step_size`
+     * so using these samples we will find a sampled SA entry in at most `suffix_array_sampling_step_size`
      * steps.
+     *
+     * The sampled rows are marked in a bit vector, so the rank of a sampled row gives the index
+     * of its entry in the packed array of samples. Only 1 row in `suffix_array_sampling_step_size`
+     * is marked, so a sparse bit vector takes far less than a bit per row. Every sampled entry is
+     * a multiple of the sampling step, so dividing by it saves log2(step) bits per entry.
      */
-    return suffix_array
+    let seq_len = suffix_array.len();
+    let (sampled_rows, sampled_entries): (Vec<usize>, Vec<usize>) = suffix_array
         .into_iter()
         .enumerate()
         .filter(|(_, entry)| entry % suffix_array_sampling_step_size == 0)
-        .collect(); // Maps index in original SA to entry in SA
+        .map(|(index, entry)| (index, entry / suffix_array_sampling_step_size))
+        .unzip(); // Maps index in original SA to entry in SA
+
+    return (
+        SparseBitVector::new(seq_len, sampled_rows),
+        PackedArray::new(&sampled_entries),
+    );
+}
+
+/*
+ * Samples the inverse of the suffix array, mapping every `suffix_array_sampling_step_size`th
+ * position in the original string to the row of the BW matrix starting at that position.
+ * This lets us find a row to start walking back from when extracting part of the string.
+ */
+fn sample_inverse_suffix_array(
+    suffix_array: &[usize],
+    suffix_array_sampling_step_size: usize,
+) -> PackedArray {
+    let mut sampled_inverse_suffix_array =
+        vec![0; suffix_array.len().div_ceil(suffix_array_sampling_step_size)];
+
+    for (index, &entry) in suffix_array.iter().enumerate() {
+        if entry % suffix_array_sampling_step_size == 0 {
+            sampled_inverse_suffix_array[entry / suffix_array_sampling_step_size] = index;
+        }
+    }
+
+    return PackedArray::new(&sampled_inverse_suffix_array);
+}
+
+/*
+ * Whether the rows starting with each nucleotide follow the sentinel's row in order, within the
+ * BW matrix. `RANKED_NUCLEOTIDES` are in sorted order.
+ */
+fn first_bwt_column_index_consistent(
+    first_bwt_column_index: &NucStratified<usize>,
+    seq_len: usize,
+) -> bool {
+    let first_rows: Vec<usize> = RANKED_NUCLEOTIDES
+        .iter()
+        .map(|&nucleotide| *first_bwt_column_index.get(nucleotide))
+        .collect();
+    return seq_len > 0
+        && first_rows[0] == 1
+        && first_rows.windows(2).all(|rows| rows[0] <= rows[1])
+        && first_rows[first_rows.len() - 1] <= seq_len;
+}
+
+// Reads a run-length encoded BWT and its rank checkpoints, written by `FMIndex::write_to`
+fn read_run_length_bwt(
+    reader: &mut IndexReader,
+    seq_len: usize,
+    rank_sampling_step_size: usize,
+) -> Result<Bwt, IndexFileError> {
+    let compressed_bwt = RunLengthEncodedString::read_from(reader, seq_len)?;
+    let mut ranks = NucStratified::default();
+    for nucleotide in RANKED_NUCLEOTIDES {
+        *ranks.get_mut(nucleotide) = reader.read_array()?;
+    }
+
+    /*
+     * Each checkpoint counts the nucleotides up to and including its row, which is every row but
+     * the sentinel's
+     */
+    let rank_checkpoint_count = seq_len.div_ceil(rank_sampling_step_size);
+    let checkpoint_consistent = |checkpoint_index: usize| {
+        let row = checkpoint_index * rank_sampling_step_size;
+        let count = RANKED_NUCLEOTIDES
+            .iter()
+            .try_fold(0usize, |count, &nucleotide| {
+                return count.checked_add(ranks.get(nucleotide)[checkpoint_index]);
+            });
+        return count == Some(row) || count == Some(row + 1);
+    };
+    if RANKED_NUCLEOTIDES
+        .iter()
+        .any(|&nucleotide| ranks.get(nucleotide).len() != rank_checkpoint_count)
+        || !(0..rank_checkpoint_count).all(checkpoint_consistent)
+    {
+        return Err(IndexFileError::Corrupt(
+            "inconsistent rank checkpoints".to_string(),
+        ));
+    }
+
+    return Ok(Bwt::RunLength {
+        compressed_bwt,
+        ranks,
+    });
+}
+
+// Counts each nucleotide in the BWT up to and including every `rank_sampling_step_size`th row
+fn sample_ranks(bwt: &str, rank_sampling_step_size: usize) -> NucStratified<FlatArray<usize>> {
+    let mut counts: NucStratified<usize> = NucStratified::default();
+    let mut ranks: NucStratified<Vec<usize>> = NucStratified::default();
+
+    for (i, nuc) in bwt.chars().enumerate() {
+        if nuc != '$' {
+            *counts.get_mut(nuc) += 1;
+        }
+
+        if i % rank_sampling_step_size == 0 {
+            for nuc_key in RANKED_NUCLEOTIDES {
+                let current_nuc_count = *counts.get(nuc_key);
+                ranks.get_mut(nuc_key).push(current_nuc_count);
+            }
+        }
+    }
+
+    return NucStratified {
+        a: ranks.a.into(),
+        c: ranks.c.into(),
+        g: ranks.g.into(),
+        n: ranks.n.into(),
+        t: ranks.t.into(),
+    };
 }
 
-fn construct_bwt(str: &str, suffix_array: &Vec<usize>) -> String {
+fn construct_bwt(str: &str, suffix_array: &[usize]) -> String {
     let str_bytes = str.as_bytes();
     let str_len = str.len();
     /*
@@ -292,7 +723,12 @@ fn get_first_bwt_column_index(nuc_counts: &NucStratified<usize>) -> NucStratifie
         a: 1,
         c: 1 + nuc_counts.get('A'),
         g: 1 + nuc_counts.get('A') + nuc_counts.get('C'),
-        t: 1 + nuc_counts.get('A') + nuc_counts.get('C') + nuc_counts.get('G'),
+        // N sorts between G and T
+        n: 1 + nuc_counts.get('A') + nuc_counts.get('C') + nuc_counts.get('G'),
+        t: 1 + nuc_counts.get('A')
+            + nuc_counts.get('C')
+            + nuc_counts.get('G')
+            + nuc_counts.get(NON_NUCLEOTIDE),
     };
 }
 
@@ -312,12 +748,103 @@ mod tests {
     fn count_extension_matches() {
         let fm_index = FMIndex::new("ACGCGCTTCGCCTT$", 3, 4);
         // Partial match against chars preceding CCTT$
-        assert_eq!(fm_index.count_extension_matches(2, "GCTAAA"), 3);
+        assert_eq!(
+            fm_index
+                .count_extension_matches(2, "GCTAAA", 0, 20)
+                .match_length,
+            3
+        );
 
-        assert_eq!(fm_index.count_extension_matches(7, ""), 0);
+        assert_eq!(
+            fm_index.count_extension_matches(7, "", 0, 20).match_length,
+            0
+        );
 
         // Full match against chars preceding GCTTCGCCTT$
-        assert_eq!(fm_index.count_extension_matches(10, "CGCA"), 4);
+        assert_eq!(
+            fm_index
+                .count_extension_matches(10, "CGCA", 0, 20)
+                .match_length,
+            4
+        );
+    }
+
+    #[test]
+    fn count_extension_matches_with_mismatches() {
+        let fm_index = FMIndex::new("ACGCGCTTCGCCTT$", 3, 4);
+
+        // Chars preceding CCTT$ are GCTTCGCGCA$
+        assert_eq!(
+            fm_index.count_extension_matches(2, "GCTACGCGCA", 1, 20),
+            ExtensionResult {
+                match_length: 10,
+                mismatch_positions: vec![3]
+            }
+        );
+
+        // Too many mismatches, so stop before the second one
+        assert_eq!(
+            fm_index.count_extension_matches(2, "GCTACGAGCA", 1, 20),
+            ExtensionResult {
+                match_length: 3,
+                mismatch_positions: vec![]
+            }
+        );
+
+        // Score drops too far after the mismatch
+        assert_eq!(
+            fm_index.count_extension_matches(2, "GCTACGCGCA", 1, 2),
+            ExtensionResult {
+                match_length: 3,
+                mismatch_positions: vec![]
+            }
+        );
+
+        // Stops at the start of the genome
+        assert_eq!(
+            fm_index.count_extension_matches(2, "GCTTCGCGCAT", 1, 20),
+            ExtensionResult {
+                match_length: 10,
+                mismatch_positions: vec![]
+            }
+        );
+    }
+
+    #[test]
+    fn non_nucleotides_are_hard_stops() {
+        let fm_index = FMIndex::new("ACGTTGNCATGCA$", 3, 4);
+
+        // Present either side of the N, but never across it
+        assert!(fm_index.lookup("TTG").eq(13..14));
+        assert!(fm_index.lookup("CAT").eq(5..6));
+        assert!(fm_index.lookup("GNC").eq(0..0));
+        assert!(fm_index.lookup("GCAT").eq(0..0));
+
+        // Chars preceding CATGCA$ are N, G, T, ...
+        let cat_index = fm_index.lookup("CATGCA").next().unwrap();
+        assert_eq!(
+            fm_index.count_extension_matches(cat_index, "NGTTG", 5, 20),
+            ExtensionResult {
+                match_length: 0,
+                mismatch_positions: vec![]
+            }
+        );
+
+        // Walking back through the N still works for locating and extracting
+        assert_eq!(fm_index.get_genome_position(cat_index), 7);
+        assert_eq!(fm_index.extract(2, 10), "GTTGNCAT");
+    }
+
+    #[test]
+    fn extract() {
+        let fm_index = FMIndex::new("ACGCGCTTCGCCTT$", 3, 4);
+        let genome = "ACGCGCTTCGCCTT";
+
+        for start in 0..genome.len() {
+            for end in start..=genome.len() {
+                assert_eq!(fm_index.extract(start, end), &genome[start..end]);
+            }
+        }
     }
 
     #[test]
@@ -349,4 +876,64 @@ mod tests {
 
         assert_eq!(actual_sa_values, expected_sa_values);
     }
+
+    #[test]
+    fn packed_bwt_matches_run_length() {
+        // Contigs separated by N, spanning several blocks of the packed BWT
+        let genome = "ACGCGCTTCGCCTTAGGATTACANNGATTACAGGCATCGATCGGGCTANTTACGATCAGGCATTTACGGCAT";
+        let str = format!("{genome}$");
+
+        let run_length = FMIndex::new(&str, 3, 32);
+        let packed = FMIndex::with_bwt_storage(&str, 3, 32, BwtStorage::Packed);
+        assert_eq!(packed.bwt_storage(), BwtStorage::Packed);
+
+        for row in 0..str.len() {
+            assert_eq!(packed.get_bwt_char(row), run_length.get_bwt_char(row));
+            assert_eq!(
+                packed.get_genome_position(row),
+                run_length.get_genome_position(row)
+            );
+        }
+
+        for target in ["A", "GAT", "TTACG", "CAN", "GGCAT", "TTTT"] {
+            assert!(packed.lookup(target).eq(run_length.lookup(target)));
+        }
+
+        for start in (0..genome.len()).step_by(5) {
+            assert_eq!(packed.extract(start, genome.len()), &genome[start..]);
+        }
+
+        let match_index = packed.lookup("GGCAT").next().unwrap();
+        assert_eq!(
+            packed.count_extension_matches(match_index, "CGATCGGG", 2, 10),
+            run_length.count_extension_matches(match_index, "CGATCGGG", 2, 10)
+        );
+    }
+
+    #[test]
+    fn sampled_suffix_array_memory() {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let genome: String = (0..20_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                return b"ACGT"[(state % 4) as usize] as char;
+            })
+            .collect();
+        let fm_index = FMIndex::new(&format!("{genome}$"), 128, 128);
+
+        // A `HashMap<usize, usize>` of the samples takes at least 2 words for each
+        let sample_count = fm_index.sampled_suffix_array.len();
+        let hash_map_size = sample_count * 2 * std::mem::size_of::<usize>();
+        assert!(fm_index.memory_usage().suffix_array * 4 < hash_map_size);
+
+        // Every row is still located, to a different position
+        let positions: Vec<_> = (0..fm_index.seq_len())
+            .map(|row| fm_index.get_genome_position(row))
+            .collect();
+        let mut sorted_positions = positions.clone();
+        sorted_positions.sort();
+        assert!(sorted_positions.into_iter().eq(0..fm_index.seq_len()));
+    }
 }
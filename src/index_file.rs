@@ -0,0 +1,623 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::sync::Arc;
+
+use bincode::{
+    config::standard, decode_from_std_read, encode_to_vec, error::DecodeError, Decode, Encode,
+};
+use bytemuck::Pod;
+use flate2::Crc;
+use memmap2::Mmap;
+
+use crate::flat_array::FlatArray;
+use crate::read_mapping_index::{AmbiguousSpan, Contig};
+
+/*
+ * Layout of a saved `ReadMappingIndex`:
+ *
+ *   MAGIC | FORMAT_VERSION (u32, little endian) | IndexHeader | body
+ *
+ * The magic bytes and version come first, and are fixed, so that files of another type and
+ * indexes written by other versions are recognised before anything else is decoded. The header
+ * is bincode.
+ *
+ * The body holds the FM indexes as a sequence of words and arrays, each starting at a multiple of
+ * `ALIGNMENT` bytes. An array is its length followed by its elements. The file is memory mapped
+ * when loaded, and the arrays are used in place rather than decoded, so loading takes no time
+ * and processes mapping against the same index share its pages.
+ *
+ * Since the body is used in place, its words have the size and byte order of the machine which
+ * wrote it, as recorded in the header. Indexes can only be loaded on machines with the same
+ * `WordLayout`, eg any 64-bit little endian machine.
+ */
+
+// Identifies a file as a read mapping index
+pub const MAGIC: [u8; 8] = *b"RMAPIDX\0";
+
+// Incremented whenever the layout of the header or body changes
+pub const FORMAT_VERSION: u32 = 7;
+
+// No element is larger than 8 bytes, so arrays starting at a multiple of 8 can be used in place
+const ALIGNMENT: usize = 8;
+
+/*
+ * Most memory decoding the header may claim, far beyond the header of any real genome. Lengths in
+ * a corrupt header then fail to decode rather than being allocated.
+ */
+const MAX_HEADER_MEMORY: usize = 1 << 28;
+
+/*
+ * How densely an FM index is sampled. Smaller steps make locating and extending matches faster,
+ * at the cost of a larger index.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct SamplingSteps {
+    // Every `suffix_array`th position of the genome is sampled in the suffix array
+    pub suffix_array: usize,
+    // Nucleotides in the BWT are counted every `rank` rows
+    pub rank: usize,
+}
+
+impl Default for SamplingSteps {
+    fn default() -> Self {
+        return SamplingSteps {
+            suffix_array: 128,
+            rank: 128,
+        };
+    }
+}
+
+// How the FM indexes store their BWT
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Encode, Decode)]
+pub enum BwtStorage {
+    /*
+     * Run-length encoded, with the nucleotides counted every `SamplingSteps::rank` rows. Small
+     * when the BWT has long runs, as for repetitive genomes.
+     */
+    #[default]
+    RunLength,
+    /*
+     * 2 bits per base, in blocks of `SamplingSteps::rank` bases starting with the nucleotide
+     * counts, which must be a multiple of 32. Counting nucleotides takes a few popcounts rather
+     * than a scan of the runs, so it's faster for genomes with few repeats, eg bacteria.
+     */
+    Packed,
+}
+
+// Sampling steps and BWT storage used when building each FM index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Encode, Decode)]
+pub struct BuildParams {
+    pub forwards: SamplingSteps,
+    pub reverse: SamplingSteps,
+    pub bwt_storage: BwtStorage,
+}
+
+// Size and byte order of the words in the body of an index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct WordLayout {
+    // Bytes in a `usize`
+    pub word_size: u8,
+    pub little_endian: bool,
+}
+
+impl WordLayout {
+    // The layout of this machine, which indexes are written in
+    pub fn native() -> Self {
+        return WordLayout {
+            word_size: size_of::<usize>() as u8,
+            little_endian: cfg!(target_endian = "little"),
+        };
+    }
+}
+
+impl fmt::Display for WordLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let endianness = match self.little_endian {
+            true => "little",
+            false => "big",
+        };
+        return write!(f, "{}-bit {endianness} endian", self.word_size as usize * 8);
+    }
+}
+
+// Describes the indexed genome and how the index was built
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct IndexHeader {
+    pub word_layout: WordLayout,
+    pub build_params: BuildParams,
+    // Length of the indexed string: every contig, the separators between them and the sentinel
+    pub genome_length: usize,
+    // `genome_checksum` of the indexed string, without the sentinel
+    pub genome_checksum: u32,
+    pub contigs: Vec<Contig>,
+    pub ambiguous_spans: Vec<AmbiguousSpan>,
+}
+
+#[derive(Debug)]
+pub enum IndexFileError {
+    Io(io::Error),
+    // The file doesn't start with `MAGIC`, eg a FASTA file, or an index saved before the header
+    // was introduced
+    NotAnIndex,
+    // The index was written in another format version, and must be rebuilt
+    UnsupportedVersion { found: u32, supported: u32 },
+    // The index was written on a machine with another word size or byte order
+    IncompatibleWordLayout { found: WordLayout },
+    // The file ends part way through the index
+    Truncated,
+    // The file has the right header, but its contents can't be decoded or are inconsistent
+    Corrupt(String),
+    // The genome read back out of the index doesn't match the checksum in the header
+    ChecksumMismatch { expected: u32, found: u32 },
+}
+
+impl fmt::Display for IndexFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexFileError::Io(error) => return write!(f, "{error}"),
+            IndexFileError::NotAnIndex => {
+                return write!(
+                    f,
+                    "not a read mapping index, or one saved before format version 1"
+                )
+            }
+            IndexFileError::UnsupportedVersion { found, supported } if found < supported => {
+                return write!(
+                    f,
+                    "index format version {found} is older than the supported version {supported}, \
+                     rebuild the index"
+                )
+            }
+            IndexFileError::UnsupportedVersion { found, supported } => {
+                return write!(
+                    f,
+                    "index format version {found} is newer than the supported version {supported}"
+                )
+            }
+            IndexFileError::IncompatibleWordLayout { found } => {
+                return write!(
+                    f,
+                    "index was written on a {found} machine, and this one is {}, rebuild the index",
+                    WordLayout::native()
+                )
+            }
+            IndexFileError::Truncated => return write!(f, "index file is truncated"),
+            IndexFileError::Corrupt(reason) => return write!(f, "index file is corrupt: {reason}"),
+            IndexFileError::ChecksumMismatch { expected, found } => {
+                return write!(
+                    f,
+                    "genome checksum {found:08x} doesn't match {expected:08x} in the index header"
+                )
+            }
+        }
+    }
+}
+
+impl Error for IndexFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            IndexFileError::Io(error) => return Some(error),
+            _ => return None,
+        }
+    }
+}
+
+impl From<io::Error> for IndexFileError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            return IndexFileError::Truncated;
+        }
+        return IndexFileError::Io(error);
+    }
+}
+
+impl From<DecodeError> for IndexFileError {
+    fn from(error: DecodeError) -> Self {
+        match error {
+            DecodeError::UnexpectedEnd { .. } => return IndexFileError::Truncated,
+            DecodeError::Io { inner, .. } => return IndexFileError::from(inner),
+            _ => return IndexFileError::Corrupt(error.to_string()),
+        }
+    }
+}
+
+// CRC-32 of the indexed string, identifying the genome (after ambiguous bases are masked)
+pub fn genome_checksum(genome: &str) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(genome.as_bytes());
+    return crc.sum();
+}
+
+// Writes the header and body of an index, keeping track of the alignment of the body
+pub(crate) struct IndexWriter<W: Write> {
+    writer: W,
+    position: usize,
+}
+
+impl<W: Write> IndexWriter<W> {
+    pub(crate) fn new(writer: W) -> Self {
+        return IndexWriter {
+            writer,
+            position: 0,
+        };
+    }
+
+    pub(crate) fn write_header(&mut self, header: &IndexHeader) -> io::Result<()> {
+        self.write_bytes(&MAGIC)?;
+        self.write_bytes(&FORMAT_VERSION.to_le_bytes())?;
+
+        let header = encode_to_vec(header, standard()).map_err(io::Error::other)?;
+        self.write_bytes(&header)?;
+        return self.pad();
+    }
+
+    pub(crate) fn write_word(&mut self, word: usize) -> io::Result<()> {
+        self.write_bytes(&word.to_ne_bytes())?;
+        return self.pad();
+    }
+
+    pub(crate) fn write_array<T: Pod>(&mut self, elements: &[T]) -> io::Result<()> {
+        self.write_word(elements.len())?;
+        self.write_bytes(bytemuck::cast_slice(elements))?;
+        return self.pad();
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        return self.writer;
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.position += bytes.len();
+        return Ok(());
+    }
+
+    // Pads with zeros up to the next multiple of `ALIGNMENT`
+    fn pad(&mut self) -> io::Result<()> {
+        let padding = self.position.next_multiple_of(ALIGNMENT) - self.position;
+        return self.write_bytes(&[0; ALIGNMENT][..padding]);
+    }
+}
+
+// Reads the body of a memory mapped index, in the order it was written by `IndexWriter`
+pub(crate) struct IndexReader {
+    mmap: Arc<Mmap>,
+    position: usize,
+}
+
+impl IndexReader {
+    // Maps the file, returning its header and a reader positioned at the start of the body
+    pub(crate) fn open(path: &str) -> Result<(IndexHeader, Self), IndexFileError> {
+        let file = File::open(path)?;
+        // Safe as long as the file isn't modified while mapped, which `from_file` documents
+        let mmap = Arc::new(unsafe { Mmap::map(&file)? });
+
+        let mut remaining: &[u8] = &mmap;
+        let header = read_header(&mut remaining)?;
+        if header.word_layout != WordLayout::native() {
+            return Err(IndexFileError::IncompatibleWordLayout {
+                found: header.word_layout,
+            });
+        }
+        let position = (mmap.len() - remaining.len()).next_multiple_of(ALIGNMENT);
+
+        return Ok((header, IndexReader { mmap, position }));
+    }
+
+    pub(crate) fn read_word(&mut self) -> Result<usize, IndexFileError> {
+        let bytes = self
+            .mmap
+            .get(self.position..self.position + size_of::<usize>())
+            .ok_or(IndexFileError::Truncated)?;
+        self.position = (self.position + size_of::<usize>()).next_multiple_of(ALIGNMENT);
+
+        return Ok(usize::from_ne_bytes(bytes.try_into().unwrap()));
+    }
+
+    pub(crate) fn read_array<T: Pod>(&mut self) -> Result<FlatArray<T>, IndexFileError> {
+        let length = self.read_word()?;
+
+        let end = length
+            .checked_mul(size_of::<T>())
+            .and_then(|byte_length| byte_length.checked_add(self.position))
+            .ok_or_else(|| IndexFileError::Corrupt(format!("array of {length} elements")))?;
+        if end > self.mmap.len() {
+            return Err(IndexFileError::Truncated);
+        }
+
+        // The start of the body is aligned in the mapping, so this only fails if it's corrupt
+        let array = FlatArray::mapped(&self.mmap, self.position..end)
+            .ok_or_else(|| IndexFileError::Corrupt("misaligned array".to_string()))?;
+        self.position = end.next_multiple_of(ALIGNMENT);
+
+        return Ok(array);
+    }
+
+    // Checks that the whole body has been read
+    pub(crate) fn finish(self) -> Result<(), IndexFileError> {
+        if self.position > self.mmap.len() {
+            return Err(IndexFileError::Truncated);
+        }
+        if self.position < self.mmap.len() {
+            return Err(IndexFileError::Corrupt(
+                "unexpected data after the index".to_string(),
+            ));
+        }
+        return Ok(());
+    }
+}
+
+/*
+ * Reads the header, leaving `reader` at the start of the body. Fails without decoding the header
+ * if the file isn't an index, or is in another format version.
+ */
+pub fn read_header<R: Read>(reader: &mut R) -> Result<IndexHeader, IndexFileError> {
+    let mut magic = [0; MAGIC.len()];
+    match reader.read_exact(&mut magic) {
+        Ok(()) if magic == MAGIC => {}
+        Ok(()) => return Err(IndexFileError::NotAnIndex),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(IndexFileError::NotAnIndex)
+        }
+        Err(error) => return Err(IndexFileError::Io(error)),
+    }
+
+    let mut version = [0; 4];
+    reader.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != FORMAT_VERSION {
+        return Err(IndexFileError::UnsupportedVersion {
+            found: version,
+            supported: FORMAT_VERSION,
+        });
+    }
+
+    let config = standard().with_limit::<MAX_HEADER_MEMORY>();
+    return Ok(decode_from_std_read(reader, config)?);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fasta_parsing::FastaRecord;
+    use crate::packed_bwt::PackedBwt;
+    use crate::read_mapping_index::{ReadMappingIndex, ReadMappingIndexBuilder};
+    use crate::run_length_encoding::RunLengthEncodedString;
+    use crate::succinct::{PackedArray, SparseBitVector};
+
+    // Builds an index of 2 contigs with `builder`, and returns it with its saved bytes
+    fn saved_index(name: &str, builder: ReadMappingIndexBuilder) -> (ReadMappingIndex, Vec<u8>) {
+        let read_mapping_index = builder
+            .build(&[
+                FastaRecord::new("chr1", "ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT"),
+                FastaRecord::new("chr2", "GGCATTRRACGTAACGG"),
+            ])
+            .unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("read_mapping_{name}_{}.index", std::process::id()));
+        read_mapping_index.to_file(path.to_str().unwrap()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        return (read_mapping_index, bytes);
+    }
+
+    // Writes `bytes` to a file and tries to load it as an index
+    fn load(name: &str, bytes: &[u8]) -> Result<ReadMappingIndex, IndexFileError> {
+        let path =
+            std::env::temp_dir().join(format!("read_mapping_{name}_{}.index", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        let result = ReadMappingIndex::from_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        return result;
+    }
+
+    // Writes a header followed by the body written by `write_body`, and opens it to read the body
+    fn body_reader(
+        name: &str,
+        write_body: impl FnOnce(&mut IndexWriter<&mut Vec<u8>>) -> io::Result<()>,
+    ) -> IndexReader {
+        let header = ReadMappingIndex::new(&[FastaRecord::new("chr1", "ACGT")])
+            .unwrap()
+            .header();
+
+        let mut bytes = Vec::new();
+        let mut writer = IndexWriter::new(&mut bytes);
+        writer.write_header(&header).unwrap();
+        write_body(&mut writer).unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("read_mapping_{name}_{}.index", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let (_, reader) = IndexReader::open(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        return reader;
+    }
+
+    #[test]
+    fn round_trip() {
+        let (read_mapping_index, bytes) = saved_index("round_trip", ReadMappingIndexBuilder::new());
+        assert!(bytes.starts_with(&MAGIC));
+
+        let header = read_header(&mut bytes.as_slice()).unwrap();
+        assert_eq!(header.build_params, BuildParams::default());
+        assert_eq!(header.contigs, read_mapping_index.contigs());
+        assert_eq!(
+            header.genome_checksum,
+            genome_checksum("ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTTNGGCATTNNACGTAACGG")
+        );
+
+        let loaded = load("round_trip", &bytes).unwrap();
+        assert_eq!(loaded.contigs(), read_mapping_index.contigs());
+        assert_eq!(
+            loaded.ambiguous_spans(),
+            read_mapping_index.ambiguous_spans()
+        );
+        assert_eq!(loaded.genome_checksum(), header.genome_checksum);
+        loaded.verify().unwrap();
+
+        let read = "TATCTCCTTCGTTTACG";
+        assert_eq!(
+            loaded.map_read(read, 8, 1),
+            read_mapping_index.map_read(read, 8, 1)
+        );
+        assert!(loaded.map_read(read, 8, 1).is_some());
+    }
+
+    #[test]
+    fn packed_bwt_round_trip() {
+        let (read_mapping_index, bytes) = saved_index(
+            "packed_bwt",
+            ReadMappingIndexBuilder::new().bwt_storage(BwtStorage::Packed),
+        );
+
+        let header = read_header(&mut bytes.as_slice()).unwrap();
+        assert_eq!(header.build_params.bwt_storage, BwtStorage::Packed);
+
+        let loaded = load("packed_bwt", &bytes).unwrap();
+        assert_eq!(loaded.build_params(), read_mapping_index.build_params());
+        loaded.verify().unwrap();
+
+        let read = "TATCTCCTTCGTTTACG";
+        assert_eq!(
+            loaded.map_read(read, 8, 1),
+            saved_index("packed_bwt_default", ReadMappingIndexBuilder::new())
+                .0
+                .map_read(read, 8, 1)
+        );
+    }
+
+    #[test]
+    fn load_failures() {
+        let (_, bytes) = saved_index("load_failures", ReadMappingIndexBuilder::new());
+
+        assert!(matches!(
+            load("missing_magic", b">chr1\nACGT\n"),
+            Err(IndexFileError::NotAnIndex)
+        ));
+        assert!(matches!(
+            load("empty", b""),
+            Err(IndexFileError::NotAnIndex)
+        ));
+
+        let mut old_version = bytes.clone();
+        old_version[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(
+            load("old_version", &old_version),
+            Err(IndexFileError::UnsupportedVersion {
+                found: 0,
+                supported: FORMAT_VERSION
+            })
+        ));
+
+        // Written on a 32-bit big endian machine, so the words can't be read
+        let mut header = read_header(&mut bytes.as_slice()).unwrap();
+        let header_length = encode_to_vec(&header, standard()).unwrap().len();
+        header.word_layout = WordLayout {
+            word_size: 4,
+            little_endian: false,
+        };
+        let mut other_word_layout = bytes.clone();
+        other_word_layout.splice(
+            MAGIC.len() + 4..MAGIC.len() + 4 + header_length,
+            encode_to_vec(&header, standard()).unwrap(),
+        );
+        assert!(matches!(
+            load("other_word_layout", &other_word_layout),
+            Err(IndexFileError::IncompatibleWordLayout { found }) if found == header.word_layout
+        ));
+
+        let mut trailing_data = bytes.clone();
+        trailing_data.extend_from_slice(&[0; ALIGNMENT]);
+        assert!(matches!(
+            load("trailing_data", &trailing_data),
+            Err(IndexFileError::Corrupt(_))
+        ));
+
+        // A contig count which would need more memory than the machine has
+        let header = read_header(&mut bytes.as_slice()).unwrap();
+        let header_start = MAGIC.len() + 4;
+        let contigs_start = header_start
+            + encode_to_vec(
+                (
+                    header.word_layout,
+                    header.build_params,
+                    header.genome_length,
+                    header.genome_checksum,
+                ),
+                standard(),
+            )
+            .unwrap()
+            .len();
+        let mut huge_contig_count = bytes.clone();
+        huge_contig_count.splice(
+            contigs_start..contigs_start + 1,
+            [0xfd].into_iter().chain((1u64 << 60).to_le_bytes()),
+        );
+        assert!(matches!(
+            load("huge_contig_count", &huge_contig_count),
+            Err(IndexFileError::Corrupt(_))
+        ));
+
+        for length in [MAGIC.len() + 2, MAGIC.len() + 10, bytes.len() - 1] {
+            assert!(matches!(
+                load("truncated", &bytes[..length]),
+                Err(IndexFileError::Truncated)
+            ));
+        }
+    }
+
+    #[test]
+    fn corrupt_body() {
+        // AACCCGTT in blocks of 4, so the second checkpoint is 2 characters into the CCC run
+        let run_length_encoded = |run_counts: &[usize], checkpoint_entry_indices: &[usize]| {
+            let mut reader = body_reader("corrupt_rle", |writer| {
+                writer.write_word(4)?;
+                writer.write_array(b"ACGT")?;
+                writer.write_array(run_counts)?;
+                writer.write_array(checkpoint_entry_indices)?;
+                return writer.write_array(&[0usize, 2]);
+            });
+            return RunLengthEncodedString::read_from(&mut reader, 8);
+        };
+        assert!(run_length_encoded(&[2, 3, 1, 2], &[0, 1]).is_ok());
+        assert!(matches!(
+            run_length_encoded(&[2, 3, 1, 2], &[0, 4]),
+            Err(IndexFileError::Corrupt(_))
+        ));
+        assert!(matches!(
+            run_length_encoded(&[2, 3, 1, 1], &[0, 1]),
+            Err(IndexFileError::Corrupt(_))
+        ));
+
+        // 3 buckets of 4 bits, where the second has fewer set bits before it than the first
+        let mut reader = body_reader("corrupt_bit_vector", |writer| {
+            writer.write_word(10)?;
+            writer.write_word(2)?;
+            PackedArray::new(&[0, 2, 1, 3]).write_to(writer)?;
+            return PackedArray::new(&[0, 1, 2]).write_to(writer);
+        });
+        assert!(matches!(
+            SparseBitVector::read_from(&mut reader),
+            Err(IndexFileError::Corrupt(_))
+        ));
+
+        // The nucleotide counts before the second block are all 0
+        let mut reader = body_reader("corrupt_packed_bwt", |writer| {
+            writer.write_word(32)?;
+            writer.write_word(10)?;
+            writer.write_array(&[0u64; 10])?;
+            return writer.write_array::<usize>(&[]);
+        });
+        assert!(matches!(
+            PackedBwt::read_from(&mut reader, 64, 32),
+            Err(IndexFileError::Corrupt(_))
+        ));
+    }
+}
@@ -0,0 +1,20 @@
+pub mod alignment;
+pub mod bam;
+pub mod cigar;
+pub mod fasta_parsing;
+pub mod fastq_parsing;
+pub mod flat_array;
+pub mod fm_index;
+pub mod index_file;
+pub mod mapping_quality;
+pub mod nucleotide_stratified;
+pub mod output;
+pub mod packed_bwt;
+pub mod paf;
+pub mod paired_end;
+pub mod parallel;
+pub mod read_mapping_index;
+pub mod run_length_encoding;
+pub mod sam;
+pub mod splicing;
+pub mod succinct;
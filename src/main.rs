@@ -1,52 +1,423 @@
-mod fasta_parsing;
-mod fastq_parsing;
-mod fm_index;
-mod nucleotide_stratified;
-mod read_mapping_index;
-mod run_length_encoding;
+use std::error::Error;
+use std::num::NonZeroUsize;
+use std::process::ExitCode;
 
-use core::iter::Iterator;
-use std::env;
+use clap::{Args, Parser, Subcommand};
 
-use fasta_parsing::read_fasta;
-use fastq_parsing::read_fastq;
+use read_mapping::fasta_parsing::read_fasta;
+use read_mapping::fastq_parsing::{read_fastq, read_interleaved_fastq, read_paired_fastq};
+use read_mapping::index_file::{BwtStorage, SamplingSteps, FORMAT_VERSION};
+use read_mapping::output::create_writer;
+use read_mapping::paired_end::{InsertSizeDistribution, Mate};
+use read_mapping::parallel::{ParallelMapper, DEFAULT_BATCH_SIZE};
+use read_mapping::read_mapping_index::{
+    ExtensionMode, IndexMemoryUsage, MapReadOptions, ReadMappingIndex, ReadMappingIndexBuilder,
+    TieBreak,
+};
+use read_mapping::splicing::SpliceOptions;
 
-use read_mapping_index::{MapReadResult, ReadMappingIndex};
+// Pairs mapped on their own to estimate the insert size distribution, if it isn't given
+const INSERT_SIZE_SAMPLE_SIZE: usize = 10_000;
 
-fn main() {
-    env::set_var("RUST_BACKTRACE", "1");
+#[derive(Parser)]
+#[command(version, about = "Maps sequencing reads to a genome using FM indexes")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
 
-    let genome =
-        read_fasta("data/ncbi_dataset/data/GCF_000011505.1/GCF_000011505.1_ASM1150v1_genomic.fna");
+#[derive(Subcommand)]
+enum Command {
+    /// Build the index of a genome and save it
+    Index(IndexArgs),
+    /// Map reads to an indexed genome
+    Map(Box<MapArgs>),
+    /// Describe a saved index
+    Inspect(InspectArgs),
+}
 
-    let read_mapping_index = ReadMappingIndex::new(&genome);
+#[derive(Args)]
+struct IndexArgs {
+    /// FASTA file of the genome
+    genome: String,
+    /// File to save the index to
+    #[arg(short, long)]
+    output: String,
+    /// Sample every nth suffix array entry of the forwards index: smaller is faster to map but larger
+    #[arg(long, default_value = "128")]
+    sa_sampling_step: NonZeroUsize,
+    /// Count nucleotides every nth BWT row of the forwards index: smaller is faster to map but larger
+    #[arg(long, default_value = "128")]
+    rank_sampling_step: NonZeroUsize,
+    /// Suffix array sampling step of the reverse index, if not the same as the forwards index
+    #[arg(long)]
+    reverse_sa_sampling_step: Option<NonZeroUsize>,
+    /// Rank sampling step of the reverse index, if not the same as the forwards index
+    #[arg(long)]
+    reverse_rank_sampling_step: Option<NonZeroUsize>,
+    /// Pack the BWT into 2 bits per base rather than run-length encoding it: faster to map for
+    /// genomes with few repeats. Rank sampling steps must be multiples of 32
+    #[arg(long)]
+    packed_bwt: bool,
+}
 
-    // read_mapping_index.to_file("MRSAIndex.bin");
-    // let read_mapping_index = ReadMappingIndex::from_file("MRSAIndex.bin");
+#[derive(Args)]
+struct MapArgs {
+    /// Index built by `read-mapping index`
+    #[arg(short = 'x', long)]
+    index: String,
+    /// FASTQ file of the reads, or of the first mates (R1) of paired reads
+    reads: String,
+    /// FASTQ file of the second mates (R2) of paired reads
+    mates: Option<String>,
+    /// The reads are paired, with each first mate followed by its second mate
+    #[arg(long, conflicts_with = "mates")]
+    interleaved: bool,
+    /// Output file: .bam for coordinate sorted BAM, .paf for PAF, otherwise SAM
+    #[arg(short, long)]
+    output: String,
+    /// Number of mapping threads, or 0 for a thread per core
+    #[arg(short, long, default_value_t = 0)]
+    threads: usize,
+    /// Map at most this many reads (or pairs)
+    #[arg(long)]
+    max_reads: Option<usize>,
 
-    println!("Mapping Reads");
+    #[arg(long, default_value = "25")]
+    seed_length: NonZeroUsize,
+    /// Number of seeds to try before giving up on a read
+    #[arg(long, default_value_t = 3)]
+    max_seeds: usize,
+    /// Hits to report for each unpaired read, including secondary hits
+    #[arg(long)]
+    max_hits: Option<usize>,
+    /// Reads mapping less confidently are reported as unmapped
+    #[arg(long)]
+    min_mapq: Option<u8>,
+    /// Seeds containing a base of lower quality are skipped
+    #[arg(long)]
+    min_seed_quality: Option<u8>,
+    /// Mismatches allowed when extending a seed
+    #[arg(long)]
+    max_mismatches: Option<usize>,
+    /// Stop extending a seed once the score drops this far below the best
+    #[arg(long)]
+    x_drop: Option<usize>,
+    /// Align the reads around each seed hit, allowing indels of up to this many bases
+    #[arg(long)]
+    band_width: Option<usize>,
+    /// Chain seed hits across introns, for RNA-seq reads
+    #[arg(long)]
+    spliced: bool,
+    /// Choose randomly between equally good hits using this seed, rather than the leftmost
+    #[arg(long)]
+    random_seed: Option<u64>,
 
-    let num_of_reads = 1000000;
+    /// Mean insert size of paired reads, estimated from the reads if not given
+    #[arg(long, requires = "insert_size_std_dev")]
+    insert_size_mean: Option<f64>,
+    /// Standard deviation of the insert size of paired reads
+    #[arg(long, requires = "insert_size_mean")]
+    insert_size_std_dev: Option<f64>,
+}
 
-    let reads = read_fastq("data/SRR11998244.fastq");
+#[derive(Args)]
+struct InspectArgs {
+    /// Index built by `read-mapping index`
+    index: String,
+    /// Check the genome read back out of the index against the checksum in its header
+    #[arg(long)]
+    verify: bool,
+}
+
+fn main() -> ExitCode {
+    // Usage errors exit with status 2, from clap
+    let cli = Cli::parse();
 
-    let read_results = reads
-        .take(num_of_reads)
-        .map(|read| read_mapping_index.map_read(&read, 25, 3));
+    let result = match &cli.command {
+        Command::Index(args) => index(args),
+        Command::Map(args) => map(args),
+        Command::Inspect(args) => inspect(args),
+    };
 
-    let mut seed_attempt_map = [0, 0, 0];
-    for read_result in read_results {
-        if let Some(MapReadResult { seed_attempt, .. }) = read_result {
-            seed_attempt_map[seed_attempt] += 1;
+    match result {
+        Ok(()) => return ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("read-mapping: {error}");
+            return ExitCode::FAILURE;
         }
     }
+}
+
+fn index(args: &IndexArgs) -> Result<(), Box<dyn Error>> {
+    let records = read_fasta(&args.genome)
+        .map_err(|error| format!("failed to read {}: {error}", args.genome))?;
+
+    let forwards_sampling_steps = SamplingSteps {
+        suffix_array: args.sa_sampling_step.get(),
+        rank: args.rank_sampling_step.get(),
+    };
+    let reverse_sampling_steps = SamplingSteps {
+        suffix_array: args
+            .reverse_sa_sampling_step
+            .unwrap_or(args.sa_sampling_step)
+            .get(),
+        rank: args
+            .reverse_rank_sampling_step
+            .unwrap_or(args.rank_sampling_step)
+            .get(),
+    };
+
+    let bwt_storage = match args.packed_bwt {
+        true => BwtStorage::Packed,
+        false => BwtStorage::RunLength,
+    };
+
+    eprintln!("Indexing {} contigs", records.len());
+    let read_mapping_index = ReadMappingIndexBuilder::new()
+        .forwards_sampling_steps(forwards_sampling_steps)
+        .reverse_sampling_steps(reverse_sampling_steps)
+        .bwt_storage(bwt_storage)
+        .build(&records)
+        .map_err(|error| format!("{}: {error}", args.genome))?;
+    eprintln!(
+        "Masked {} ambiguous bases",
+        read_mapping_index.masked_base_count()
+    );
+    eprint!("{}", memory_usage_table(&read_mapping_index.memory_usage()));
+
+    read_mapping_index
+        .to_file(&args.output)
+        .map_err(|error| format!("failed to write {}: {error}", args.output))?;
+
+    return Ok(());
+}
+
+fn map(args: &MapArgs) -> Result<(), Box<dyn Error>> {
+    let read_mapping_index = load_index(&args.index)?;
+    let options = map_read_options(args);
+
+    let parallel_mapper = ParallelMapper::new(args.threads, DEFAULT_BATCH_SIZE)?;
+    eprintln!("Mapping with {} threads", parallel_mapper.thread_count());
+
+    let mut writer = create_writer(&args.output, read_mapping_index.contigs())
+        .map_err(|error| format!("failed to create {}: {error}", args.output))?;
+    let max_reads = args.max_reads.unwrap_or(usize::MAX);
+
+    let paired_reads = match (&args.mates, args.interleaved) {
+        (Some(mates), _) => Some(read_paired_fastq(&args.reads, mates)),
+        (None, true) => Some(read_interleaved_fastq(&args.reads)),
+        (None, false) => None,
+    };
+
+    let Some(paired_reads) = paired_reads else {
+        let reads = read_fastq(&args.reads)
+            .map_err(|error| format!("failed to read {}: {error}", args.reads))?;
+
+        let mut read_count = 0;
+        let mut mapped_count = 0;
+        parallel_mapper.run(
+            reads
+                .take(max_reads)
+                .map(|record| record.map_err(Box::<dyn Error>::from)),
+            |record| {
+                // Reads shorter than a seed can't be mapped
+                if record.seq.len() < options.seed_length {
+                    return Vec::new();
+                }
+                return read_mapping_index.map_read_hits(&record.seq, Some(&record.qual), &options);
+            },
+            |record, hits| {
+                read_count += 1;
+                if hits.is_empty() {
+                    writer.write_record(&record, None)?;
+                    return Ok(());
+                }
+
+                mapped_count += 1;
+                for hit in &hits {
+                    writer.write_record(&record, Some(hit))?;
+                }
+                return Ok(());
+            },
+        )?;
+        writer.finish()?;
+
+        eprintln!("Mapped {mapped_count} of {read_count} reads");
+        return Ok(());
+    };
+
+    let mut pairs = paired_reads
+        .map_err(|error| format!("failed to read paired reads: {error}"))?
+        .take(max_reads);
+
+    // The pairs used to estimate the insert sizes are mapped along with the rest
+    let insert_size_sample = pairs
+        .by_ref()
+        .take(INSERT_SIZE_SAMPLE_SIZE)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let insert_sizes = match (args.insert_size_mean, args.insert_size_std_dev) {
+        (Some(mean), Some(std_dev)) => InsertSizeDistribution { mean, std_dev },
+        _ => parallel_mapper
+            .install(|| read_mapping_index.estimate_insert_sizes(&insert_size_sample, &options))
+            .ok_or(
+                "too few pairs mapped confidently to estimate the insert size, \
+                 give it with --insert-size-mean and --insert-size-std-dev",
+            )?,
+    };
+    eprintln!(
+        "Insert size {:.1} ± {:.1}",
+        insert_sizes.mean, insert_sizes.std_dev
+    );
+
+    let mut pair_count = 0;
+    let mut mapped_count = 0;
+    let mut proper_pair_count = 0;
+    parallel_mapper.run(
+        insert_size_sample
+            .into_iter()
+            .map(Ok)
+            .chain(pairs.map(|pair| pair.map_err(Box::<dyn Error>::from))),
+        |(first, second)| read_mapping_index.map_pair(first, second, &options, &insert_sizes),
+        |(first, second), paired_result| {
+            pair_count += 1;
+            if paired_result.proper_pair {
+                proper_pair_count += 1;
+            }
+
+            for (record, mate) in [(&first, Mate::First), (&second, Mate::Second)] {
+                let result = paired_result.result(mate);
+                if result.is_some() {
+                    mapped_count += 1;
+                }
+                writer.write_mate(record, result, &paired_result.mate_info(mate))?;
+            }
+            return Ok(());
+        },
+    )?;
+    writer.finish()?;
+
+    eprintln!(
+        "Mapped {mapped_count} of {} reads, with {proper_pair_count} of {pair_count} pairs properly paired",
+        pair_count * 2
+    );
+    return Ok(());
+}
+
+fn inspect(args: &InspectArgs) -> Result<(), Box<dyn Error>> {
+    let read_mapping_index = load_index(&args.index)?;
+
+    let contigs = read_mapping_index.contigs();
+    let genome_length: usize = contigs.iter().map(|contig| contig.length).sum();
+
+    let build_params = read_mapping_index.build_params();
+
+    println!("Format version\t{FORMAT_VERSION}");
+    println!(
+        "SA sampling step\t{} forwards, {} reverse",
+        build_params.forwards.suffix_array, build_params.reverse.suffix_array
+    );
+    println!(
+        "Rank sampling step\t{} forwards, {} reverse",
+        build_params.forwards.rank, build_params.reverse.rank
+    );
+    println!("BWT storage\t{:?}", build_params.bwt_storage);
+    println!(
+        "Genome checksum\t{:08x}",
+        read_mapping_index.genome_checksum()
+    );
+    println!("Contigs\t{}", contigs.len());
+    println!("Genome length\t{genome_length}");
+    println!(
+        "Masked bases\t{} in {} spans",
+        read_mapping_index.masked_base_count(),
+        read_mapping_index.ambiguous_spans().len()
+    );
+
+    println!();
+    print!("{}", memory_usage_table(&read_mapping_index.memory_usage()));
+
+    println!();
+    println!("Name\tLength");
+    for contig in contigs {
+        println!("{}\t{}", contig.name, contig.length);
+    }
+
+    if args.verify {
+        read_mapping_index
+            .verify()
+            .map_err(|error| format!("failed to verify index {}: {error}", args.index))?;
+        eprintln!("Genome checksum verified");
+    }
 
-    let successful_maps_count: usize = seed_attempt_map.iter().sum();
+    return Ok(());
+}
+
+fn load_index(path: &str) -> Result<ReadMappingIndex, Box<dyn Error>> {
+    let read_mapping_index = ReadMappingIndex::from_file(path)
+        .map_err(|error| format!("failed to load index {path}: {error}"))?;
+    return Ok(read_mapping_index);
+}
 
-    for (seed_attempt, &attempt_count) in seed_attempt_map.iter().enumerate() {
-        let percentage = f64::round(100.0 * attempt_count as f64 / successful_maps_count as f64);
-        println!("Seed attempt {seed_attempt} - {attempt_count} ({percentage}%)");
+// Bytes used by each part of the index, as a table
+fn memory_usage_table(memory_usage: &IndexMemoryUsage) -> String {
+    let (forwards, reverse) = (memory_usage.forwards, memory_usage.reverse);
+
+    let mut table = String::from("Component\tForwards bytes\tReverse bytes\n");
+    for (component, forwards_bytes, reverse_bytes) in [
+        ("BWT", forwards.bwt, reverse.bwt),
+        (
+            "Rank checkpoints",
+            forwards.rank_checkpoints,
+            reverse.rank_checkpoints,
+        ),
+        (
+            "Sampled suffix array",
+            forwards.suffix_array,
+            reverse.suffix_array,
+        ),
+        (
+            "Sampled inverse suffix array",
+            forwards.inverse_suffix_array,
+            reverse.inverse_suffix_array,
+        ),
+        ("Total", forwards.total(), reverse.total()),
+    ] {
+        table.push_str(&format!("{component}\t{forwards_bytes}\t{reverse_bytes}\n"));
+    }
+
+    return table;
+}
+
+// Options given on the command line, with the library's defaults for the rest
+fn map_read_options(args: &MapArgs) -> MapReadOptions {
+    let mut options = MapReadOptions::new(args.seed_length.get(), args.max_seeds);
+
+    if let Some(max_hits) = args.max_hits {
+        options.max_hits = max_hits;
+    }
+    if let Some(min_mapq) = args.min_mapq {
+        options.min_mapq = min_mapq;
+    }
+    if let Some(min_seed_quality) = args.min_seed_quality {
+        options.min_seed_quality = min_seed_quality;
+    }
+    if let Some(max_mismatches) = args.max_mismatches {
+        options.max_mismatches = max_mismatches;
+    }
+    if let Some(x_drop) = args.x_drop {
+        options.x_drop = x_drop;
+    }
+    if let Some(band_width) = args.band_width {
+        options.extension_mode = ExtensionMode::BandedAlignment { band_width };
+    }
+    if args.spliced {
+        options.splicing = Some(SpliceOptions::default());
+    }
+    if let Some(seed) = args.random_seed {
+        options.tie_break = TieBreak::Random { seed };
     }
 
-    println!("{successful_maps_count} reads successfully mapped out of {num_of_reads} reads");
+    return options;
 }
@@ -0,0 +1,167 @@
+use crate::alignment::AlignmentScoring;
+
+// Highest MAPQ reported, for a read with a single, complete match
+pub const MAX_MAPQ: u8 = 60;
+
+/*
+ * Estimates the Phred-scaled confidence that a read is placed correctly (MAPQ), from the score of
+ * the best match, the score of the next best match (if any) and the number of places the seed
+ * occurs in the genome (the size of its SA interval, over both strands).
+ *
+ * - Reads whose best match barely beats the next best could easily belong to either, so the
+ *   MAPQ scales with the gap between them. Equally good matches give a MAPQ of 0.
+ * - Matches covering less of the read are weaker evidence of where the read came from.
+ * - Seeds occurring in many places are in repeats, where the true copy is more likely to have been
+ *   missed altogether (eg because of a sequencing error in the seed).
+ *
+ * The constants are calibrated against simulated reads, see the tests below.
+ */
+pub fn mapping_quality(
+    best_score: isize,
+    second_best_score: Option<isize>,
+    hit_count: usize,
+    read_length: usize,
+    scoring: &AlignmentScoring,
+) -> u8 {
+    if best_score <= 0 {
+        return 0;
+    }
+
+    let best_score = best_score as f64;
+    let second_best_score = second_best_score.unwrap_or(0).max(0) as f64;
+    let max_score = (read_length as isize * scoring.match_score) as f64;
+
+    let uniqueness = 1.0 - second_best_score / best_score;
+    let coverage = (best_score / max_score).min(1.0);
+    let repeat_penalty = 10.0 * (hit_count.max(1) as f64).log10();
+
+    let mapq = MAX_MAPQ as f64 * uniqueness * coverage - repeat_penalty;
+
+    return mapq.round().clamp(0.0, MAX_MAPQ as f64) as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fasta_parsing::FastaRecord;
+    use crate::read_mapping_index::{reverse_complement, MapReadOptions, ReadMappingIndex, Strand};
+
+    // Deterministic xorshift generator, so the simulation is repeatable
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            return (self.0 % bound as u64) as usize;
+        }
+
+        fn sequence(&mut self, length: usize) -> String {
+            return (0..length).map(|_| b"ACGT"[self.next(4)] as char).collect();
+        }
+
+        // Substitutes each base with probability 1 / `one_in`
+        fn mutate(&mut self, sequence: &str, one_in: usize) -> String {
+            return sequence
+                .chars()
+                .map(|nuc| match self.next(one_in) {
+                    0 => {
+                        let nuc_index = b"ACGT".iter().position(|&c| c as char == nuc).unwrap();
+                        b"ACGT"[(nuc_index + 1 + self.next(3)) % 4] as char
+                    }
+                    _ => nuc,
+                })
+                .collect();
+        }
+    }
+
+    #[test]
+    fn scores() {
+        let scoring = AlignmentScoring::default();
+
+        assert_eq!(mapping_quality(100, None, 1, 100, &scoring), MAX_MAPQ);
+        assert_eq!(mapping_quality(100, Some(100), 2, 100, &scoring), 0);
+        assert_eq!(mapping_quality(0, None, 1, 100, &scoring), 0);
+
+        // Worse when the next best match is close, when less of the read matches and for repeats
+        let unique = mapping_quality(100, Some(40), 2, 100, &scoring);
+        assert!(mapping_quality(100, Some(80), 2, 100, &scoring) < unique);
+        assert!(mapping_quality(50, Some(20), 2, 100, &scoring) < unique);
+        assert!(mapping_quality(100, Some(40), 20, 100, &scoring) < unique);
+    }
+
+    #[test]
+    fn calibrated_on_simulated_reads() {
+        let mut random = Random(0x2545F4914F6CDD1D);
+
+        // Random genome with diverged copies of a repeat element and an exact segmental duplicate
+        let repeat = random.sequence(300);
+        let duplicate = random.sequence(500);
+        let mut genome = String::new();
+        for copy in 0..6 {
+            genome += &random.sequence(2000);
+            genome += &random.mutate(&repeat, 25);
+            if copy % 3 == 0 {
+                genome += &duplicate;
+            }
+        }
+        genome += &random.sequence(2000);
+
+        let read_mapping_index =
+            ReadMappingIndex::new(&[FastaRecord::new("genome", &genome)]).unwrap();
+
+        let mut options = MapReadOptions::new(20, 3);
+        options.max_mismatches = 5;
+
+        let read_length = 100;
+        // Reads mapped and reads mapped to the wrong place, for each MAPQ
+        let mut mapped_counts = [0usize; MAX_MAPQ as usize + 1];
+        let mut wrong_counts = [0usize; MAX_MAPQ as usize + 1];
+
+        for _ in 0..3000 {
+            let position = random.next(genome.len() - read_length);
+            let mut read = random.mutate(&genome[position..position + read_length], 100);
+            let strand = match random.next(2) {
+                0 => Strand::Forward,
+                _ => Strand::Reverse,
+            };
+            if strand == Strand::Reverse {
+                read = reverse_complement(&read);
+            }
+
+            let Some(result) = read_mapping_index.map_read_with_options(&read, &options) else {
+                continue;
+            };
+
+            // Position of the start of the read if the whole read had matched
+            let mapped_position = result.position as isize
+                - match result.strand {
+                    Strand::Forward => result.read_start,
+                    Strand::Reverse => read_length - result.read_end,
+                } as isize;
+
+            mapped_counts[result.mapq as usize] += 1;
+            if result.strand != strand || mapped_position != position as isize {
+                wrong_counts[result.mapq as usize] += 1;
+            }
+        }
+
+        // Reads with a MAPQ of 0 could equally have come from elsewhere, so often don't
+        assert!(wrong_counts[0] * 4 > mapped_counts[0]);
+
+        /*
+         * A MAPQ of q claims the read is wrongly placed with probability 10^(-q/10), so for
+         * confidently mapped reads there should be at most as many wrong placements as predicted
+         */
+        let predicted_wrong_count: f64 = (1..=MAX_MAPQ as usize)
+            .map(|mapq| mapped_counts[mapq] as f64 * 10f64.powf(-(mapq as f64) / 10.0))
+            .sum();
+        let wrong_count: usize = wrong_counts[1..].iter().sum();
+        assert!(wrong_count as f64 <= predicted_wrong_count);
+
+        // Most reads come from unique parts of the genome, so should be confidently placed
+        let confident_count: usize = mapped_counts[30..].iter().sum();
+        assert!(confident_count * 10 > mapped_counts.iter().sum::<usize>() * 8);
+    }
+}
@@ -1,12 +1,12 @@
-use bincode::{Encode, Decode};
-
 use std::fmt::Debug;
 
-#[derive(Debug, Default, Encode, Decode)]
+#[derive(Debug, Default)]
 pub struct NucStratified<T: Debug> {
     pub a: T,
     pub c: T,
     pub g: T,
+    // Any base which isn't one of the other nucleotides, eg between contigs
+    pub n: T,
     pub t: T
 }
 
@@ -16,6 +16,7 @@ impl<T: Debug> NucStratified<T> {
             'A' => &self.a,
             'C' => &self.c,
             'G' => &self.g,
+            'N' => &self.n,
             'T' => &self.t,
              _  => panic!("{nucleotide} IS NOT A NUCLEOTIDE!") 
         }
@@ -26,6 +27,7 @@ impl<T: Debug> NucStratified<T> {
             'A' => &mut self.a,
             'C' => &mut self.c,
             'G' => &mut self.g,
+            'N' => &mut self.n,
             'T' => &mut self.t,
              _  => panic!("{nucleotide} IS NOT A NUCLEOTIDE!") 
         }
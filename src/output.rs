@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use crate::bam::{BamWriter, SortOptions};
+use crate::fastq_parsing::FastqRecord;
+use crate::paf::PafWriter;
+use crate::paired_end::MateInfo;
+use crate::read_mapping_index::{Contig, MapReadResult};
+use crate::sam::SamWriter;
+
+// Common interface of the SAM, BAM and PAF writers
+pub trait AlignmentWriter {
+    // Writes the read, which is unmapped if `result` is `None`
+    fn write_record(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+    ) -> io::Result<()>;
+
+    // Writes one mate of a paired read, which is unmapped if `result` is `None`
+    fn write_mate(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+        mate_info: &MateInfo,
+    ) -> io::Result<()>;
+
+    // Writes anything still buffered. Must be called once all reads have been written
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+impl<W: io::Write> AlignmentWriter for SamWriter<'_, W> {
+    fn write_record(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+    ) -> io::Result<()> {
+        return SamWriter::write_record(self, record, result);
+    }
+
+    fn write_mate(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+        mate_info: &MateInfo,
+    ) -> io::Result<()> {
+        return SamWriter::write_mate(self, record, result, mate_info);
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        return self.flush();
+    }
+}
+
+impl AlignmentWriter for BamWriter {
+    fn write_record(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+    ) -> io::Result<()> {
+        return BamWriter::write_record(self, record, result);
+    }
+
+    fn write_mate(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+        mate_info: &MateInfo,
+    ) -> io::Result<()> {
+        return BamWriter::write_mate(self, record, result, mate_info);
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        return BamWriter::finish(*self);
+    }
+}
+
+impl<W: io::Write> AlignmentWriter for PafWriter<'_, W> {
+    fn write_record(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+    ) -> io::Result<()> {
+        return PafWriter::write_record(self, record, result);
+    }
+
+    // PAF has no fields describing the mate, so each mate is written as a separate read
+    fn write_mate(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+        _mate_info: &MateInfo,
+    ) -> io::Result<()> {
+        return PafWriter::write_record(self, record, result);
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        return self.flush();
+    }
+}
+
+/*
+ * Creates a writer for the format given by the file extension: `.bam` (sorted by coordinate),
+ * `.paf` or otherwise SAM.
+ */
+pub fn create_writer<'a>(
+    path: &str,
+    contigs: &'a [Contig],
+) -> io::Result<Box<dyn AlignmentWriter + 'a>> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str());
+
+    match extension {
+        Some("bam") => {
+            let bam_writer = BamWriter::create(path, contigs, Some(SortOptions::default()))?;
+            return Ok(Box::new(bam_writer));
+        }
+        Some("paf") => {
+            let file = BufWriter::new(File::create(path)?);
+            return Ok(Box::new(PafWriter::new(file, contigs)));
+        }
+        _ => {
+            let file = BufWriter::new(File::create(path)?);
+            return Ok(Box::new(SamWriter::new(file, contigs)?));
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::io::{self, Write};
+
+use crate::flat_array::FlatArray;
+use crate::fm_index::NON_NUCLEOTIDE;
+use crate::index_file::{IndexFileError, IndexReader, IndexWriter};
+
+// Blocks must hold a whole number of words of bases
+pub const BASES_PER_WORD: usize = 32;
+
+// Words at the start of each block, counting each nucleotide before the block
+const COUNT_WORDS: usize = 4;
+
+// Nucleotides which can be packed, in the order of their 2 bit codes
+const PACKED_NUCLEOTIDES: [char; 4] = ['A', 'C', 'G', 'T'];
+
+// The low bit of every base in a word
+const LOW_BITS: u64 = 0x5555_5555_5555_5555;
+
+/*
+ * BWT packed into 2 bits per base, in blocks which start with the number of each nucleotide
+ * before the block, as in Bowtie2 and BWA. Counting a nucleotide up to a position takes a
+ * popcount for each word of its block up to the position, and the counts and bases it reads are
+ * next to each other in memory.
+ *
+ * Only A, C, G and T fit in 2 bits. The sentinel and any N (between contigs, or masked) are rare,
+ * so they're packed as A, and their rows are kept separately to correct for this.
+ */
+#[derive(Debug)]
+pub struct PackedBwt {
+    blocks: FlatArray<u64>,
+    block_size: usize,
+    len: usize,
+    sentinel_row: usize,
+    // Rows of the BWT containing N, in increasing order
+    non_nucleotide_rows: FlatArray<usize>,
+}
+
+impl PackedBwt {
+    // `block_size` must be a multiple of 32, the number of bases packed into a word
+    pub fn new(bwt: &str, block_size: usize) -> Self {
+        assert!(
+            block_size > 0 && block_size.is_multiple_of(BASES_PER_WORD),
+            "packed BWT block size {block_size} is not a multiple of {BASES_PER_WORD}"
+        );
+
+        let words_per_block = words_per_block(block_size);
+        let mut blocks = vec![0u64; bwt.len().div_ceil(block_size) * words_per_block];
+
+        let mut counts = [0; COUNT_WORDS];
+        let mut sentinel_row = None;
+        let mut non_nucleotide_rows = Vec::new();
+
+        for (row, char) in bwt.chars().enumerate() {
+            let block_start = row / block_size * words_per_block;
+            if row.is_multiple_of(block_size) {
+                for (count_word, &count) in blocks[block_start..].iter_mut().zip(&counts) {
+                    *count_word = count as u64;
+                }
+            }
+
+            let code = match char {
+                '$' => {
+                    sentinel_row = Some(row);
+                    0
+                }
+                NON_NUCLEOTIDE => {
+                    non_nucleotide_rows.push(row);
+                    0
+                }
+                _ => nucleotide_code(char),
+            };
+            counts[code] += 1;
+
+            let offset = row % block_size;
+            blocks[block_start + COUNT_WORDS + offset / BASES_PER_WORD] |=
+                (code as u64) << (2 * (offset % BASES_PER_WORD));
+        }
+
+        return PackedBwt {
+            blocks: blocks.into(),
+            block_size,
+            len: bwt.len(),
+            sentinel_row: sentinel_row.expect("BWT has no sentinel"),
+            non_nucleotide_rows: non_nucleotide_rows.into(),
+        };
+    }
+
+    pub fn get_char_from_position(&self, row: usize) -> char {
+        if row == self.sentinel_row {
+            return '$';
+        }
+        if self.non_nucleotide_rows.binary_search(&row).is_ok() {
+            return NON_NUCLEOTIDE;
+        }
+
+        let (block_start, offset) = self.locate(row);
+        let word = self.blocks[block_start + COUNT_WORDS + offset / BASES_PER_WORD];
+        let code = (word >> (2 * (offset % BASES_PER_WORD))) & 0b11;
+
+        return PACKED_NUCLEOTIDES[code as usize];
+    }
+
+    // Number of occurrences of `nucleotide` in the BWT up to and including `row`
+    pub fn rank(&self, nucleotide: char, row: usize) -> usize {
+        assert!(row < self.len);
+
+        if nucleotide == NON_NUCLEOTIDE {
+            return self
+                .non_nucleotide_rows
+                .partition_point(|&n_row| n_row <= row);
+        }
+
+        let code = nucleotide_code(nucleotide);
+        let (block_start, offset) = self.locate(row);
+        let base_words = &self.blocks[block_start + COUNT_WORDS..];
+
+        // Each base of the pattern is the nucleotide, so a base of a word matches if XOR gives 0
+        let pattern = code as u64 * LOW_BITS;
+        let last_word = offset / BASES_PER_WORD;
+
+        let mut rank = self.blocks[block_start + code] as usize;
+        for &word in &base_words[..last_word] {
+            rank += count_matching_bases(word ^ pattern, u64::MAX);
+        }
+
+        // Bases after `row` in its word are masked out
+        let bases_in_last_word = offset % BASES_PER_WORD + 1;
+        let mask = match bases_in_last_word {
+            BASES_PER_WORD => u64::MAX,
+            _ => (1 << (2 * bases_in_last_word)) - 1,
+        };
+        rank += count_matching_bases(base_words[last_word] ^ pattern, mask);
+
+        // The sentinel and Ns are packed as A
+        if code == 0 {
+            rank -= self.rank(NON_NUCLEOTIDE, row);
+            if self.sentinel_row <= row {
+                rank -= 1;
+            }
+        }
+
+        return rank;
+    }
+
+    // Bytes used by the packed bases, and the rows which couldn't be packed
+    pub fn bases_size_in_bytes(&self) -> usize {
+        return self.blocks.size_in_bytes() - self.counts_size_in_bytes()
+            + self.non_nucleotide_rows.size_in_bytes();
+    }
+
+    // Bytes used by the nucleotide counts at the start of each block
+    pub fn counts_size_in_bytes(&self) -> usize {
+        return self.blocks.size_in_bytes() / words_per_block(self.block_size) * COUNT_WORDS;
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut IndexWriter<W>) -> io::Result<()> {
+        writer.write_word(self.block_size)?;
+        writer.write_word(self.sentinel_row)?;
+        writer.write_array(&self.blocks)?;
+        writer.write_array(&self.non_nucleotide_rows)?;
+        return Ok(());
+    }
+
+    // Reads a BWT written by `write_to` for a BWT of `len` characters in blocks of `block_size`
+    pub(crate) fn read_from(
+        reader: &mut IndexReader,
+        len: usize,
+        block_size: usize,
+    ) -> Result<Self, IndexFileError> {
+        let saved_block_size = reader.read_word()?;
+        let sentinel_row = reader.read_word()?;
+        let blocks = reader.read_array()?;
+        let non_nucleotide_rows = reader.read_array()?;
+
+        let packed_bwt = PackedBwt {
+            blocks,
+            block_size,
+            len,
+            sentinel_row,
+            non_nucleotide_rows,
+        };
+
+        if saved_block_size != block_size
+            || block_size == 0
+            || !block_size.is_multiple_of(BASES_PER_WORD)
+            || sentinel_row >= len
+            || packed_bwt.blocks.len() != len.div_ceil(block_size) * words_per_block(block_size)
+            || !packed_bwt.counts_consistent()
+            || !packed_bwt.non_nucleotide_rows_consistent()
+        {
+            return Err(IndexFileError::Corrupt(
+                "inconsistent packed BWT".to_string(),
+            ));
+        }
+
+        return Ok(packed_bwt);
+    }
+
+    // Whether the nucleotide counts at the start of each block add up to the rows before it
+    fn counts_consistent(&self) -> bool {
+        return self
+            .blocks
+            .chunks(words_per_block(self.block_size))
+            .enumerate()
+            .all(|(block_index, block)| {
+                let count = block[..COUNT_WORDS]
+                    .iter()
+                    .try_fold(0u64, |count, &nucleotide_count| {
+                        count.checked_add(nucleotide_count)
+                    });
+                return count == Some((block_index * self.block_size) as u64);
+            });
+    }
+
+    // Whether the rows containing N are in increasing order, within the BWT, and not the sentinel
+    fn non_nucleotide_rows_consistent(&self) -> bool {
+        return self
+            .non_nucleotide_rows
+            .windows(2)
+            .all(|rows| rows[0] < rows[1])
+            && self
+                .non_nucleotide_rows
+                .last()
+                .is_none_or(|&row| row < self.len)
+            && self
+                .non_nucleotide_rows
+                .binary_search(&self.sentinel_row)
+                .is_err();
+    }
+
+    // Index of the first word of the block containing `row`, and the offset of `row` in the block
+    fn locate(&self, row: usize) -> (usize, usize) {
+        let block_start = row / self.block_size * words_per_block(self.block_size);
+        return (block_start, row % self.block_size);
+    }
+}
+
+fn words_per_block(block_size: usize) -> usize {
+    return COUNT_WORDS + block_size / BASES_PER_WORD;
+}
+
+fn nucleotide_code(nucleotide: char) -> usize {
+    match nucleotide {
+        'A' => return 0,
+        'C' => return 1,
+        'G' => return 2,
+        'T' => return 3,
+        _ => panic!("{nucleotide} IS NOT A NUCLEOTIDE!"),
+    }
+}
+
+// Counts the bases within `mask` whose 2 bits are both 0
+fn count_matching_bases(difference: u64, mask: u64) -> usize {
+    let mismatched_bits = difference | (difference >> 1);
+    return (!mismatched_bits & LOW_BITS & mask).count_ones() as usize;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_and_char() {
+        // Long enough for several blocks and partial words, with Ns and the sentinel inside
+        let bwt: String = (0..500)
+            .map(|index: usize| match index {
+                137 => '$',
+                3 | 64 | 65 | 300 => 'N',
+                _ => ['A', 'C', 'G', 'T'][index * index % 7 % 4],
+            })
+            .collect();
+
+        for block_size in [32, 64, 128] {
+            let packed_bwt = PackedBwt::new(&bwt, block_size);
+
+            for (row, char) in bwt.char_indices() {
+                assert_eq!(packed_bwt.get_char_from_position(row), char);
+
+                for nucleotide in ['A', 'C', 'G', NON_NUCLEOTIDE, 'T'] {
+                    let expected = bwt[..=row]
+                        .chars()
+                        .filter(|&bwt_char| bwt_char == nucleotide)
+                        .count();
+                    assert_eq!(packed_bwt.rank(nucleotide, row), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn size_in_bytes() {
+        let bwt = format!("{}$", "ACGT".repeat(64));
+        let packed_bwt = PackedBwt::new(&bwt, 128);
+
+        // 3 blocks of 128 bases, each 4 words of counts and 4 of bases
+        assert_eq!(packed_bwt.counts_size_in_bytes(), 3 * 4 * 8);
+        assert_eq!(packed_bwt.bases_size_in_bytes(), 3 * 4 * 8);
+    }
+
+    #[test]
+    #[should_panic]
+    fn block_size_must_fill_words() {
+        PackedBwt::new("ACGT$", 48);
+    }
+}
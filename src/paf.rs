@@ -0,0 +1,127 @@
+use std::io::{self, Write};
+
+use crate::cigar::CigarOp;
+use crate::fastq_parsing::FastqRecord;
+use crate::read_mapping_index::{Contig, MapReadResult, Strand};
+
+/*
+ * Writes mapped reads in minimap2's Pairwise mApping Format (PAF), a line per mapped read.
+ * Unmapped reads are skipped, as minimap2 does by default.
+ */
+pub struct PafWriter<'a, W: Write> {
+    writer: W,
+    contigs: &'a [Contig],
+}
+
+impl<'a, W: Write> PafWriter<'a, W> {
+    pub fn new(writer: W, contigs: &'a [Contig]) -> Self {
+        return PafWriter { writer, contigs };
+    }
+
+    pub fn write_record(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+    ) -> io::Result<()> {
+        let Some(result) = result else {
+            return Ok(());
+        };
+
+        let contig = &self.contigs[result.contig_index];
+
+        let strand = match result.strand {
+            Strand::Forward => '+',
+            Strand::Reverse => '-',
+        };
+
+        // Primary or secondary hit
+        let hit_type = match result.secondary {
+            false => 'P',
+            true => 'S',
+        };
+
+        let entries = result.cigar.entries();
+        let aligned_length: usize = entries
+            .iter()
+            .filter(|entry| matches!(entry.op, CigarOp::Match))
+            .map(|entry| entry.count)
+            .sum();
+        // Number of alignment columns, including gaps but not introns
+        let block_length: usize = entries
+            .iter()
+            .filter(|entry| {
+                matches!(
+                    entry.op,
+                    CigarOp::Match | CigarOp::Insertion | CigarOp::Deletion
+                )
+            })
+            .map(|entry| entry.count)
+            .sum();
+
+        return writeln!(
+            self.writer,
+            "{}\t{}\t{}\t{}\t{strand}\t{}\t{}\t{}\t{}\t{}\t{block_length}\t{}\ttp:A:{hit_type}\tNM:i:{}\tAS:i:{}",
+            record.name,
+            record.seq.len(),
+            result.read_start,
+            result.read_end,
+            contig.name,
+            contig.length,
+            result.position,
+            result.position + result.cigar.genome_length(),
+            aligned_length - result.mismatch_positions.len(),
+            result.mapq,
+            result.edit_distance,
+            result.alignment_score
+        );
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        return self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fasta_parsing::FastaRecord;
+    use crate::read_mapping_index::ReadMappingIndex;
+
+    #[test]
+    fn write_paf() {
+        let read_mapping_index = ReadMappingIndex::new(&[
+            FastaRecord::new(
+                "chromosome",
+                "ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT",
+            ),
+            FastaRecord::new("plasmid", "GGACTGGCATTTTTATTACACTCAGAAACAGAACTCGGGT"),
+        ])
+        .unwrap();
+
+        let reads = [
+            ("forward", "TTATTACACTCAGAA"),
+            // The first 5 bases of the read are not in the genome
+            ("reverse", "CCCCCAGACGTAAACGAAGG"),
+            ("unmapped", "CCCCCCCCCCCCCCC"),
+        ];
+
+        let mut output = Vec::new();
+        let mut paf_writer = PafWriter::new(&mut output, read_mapping_index.contigs());
+        for (name, seq) in reads {
+            let record = FastqRecord {
+                name: String::from(name),
+                comment: None,
+                seq: String::from(seq),
+                qual: String::new(),
+            };
+            let result = read_mapping_index.map_read(seq, 10, 2);
+            paf_writer.write_record(&record, result.as_ref()).unwrap();
+        }
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "forward\t15\t0\t15\t+\tplasmid\t40\t12\t27\t15\t15\t60\ttp:A:P\tNM:i:0\tAS:i:15\n\
+             reverse\t20\t5\t20\t-\tchromosome\t48\t26\t41\t15\t15\t45\ttp:A:P\tNM:i:0\tAS:i:15\n"
+        );
+    }
+}
@@ -0,0 +1,570 @@
+use std::cmp::{max, min};
+
+use rayon::prelude::*;
+
+use crate::fastq_parsing::FastqRecord;
+use crate::read_mapping_index::{MapReadOptions, MapReadResult, ReadMappingIndex, Strand};
+
+// Number of hits considered for each mate when looking for a proper pair
+const PAIRING_HITS: usize = 10;
+
+// Pairs are only used to estimate insert sizes if both mates map at least this confidently
+const MIN_ESTIMATION_MAPQ: u8 = 20;
+
+// Proper pairs have an insert size within this many standard deviations of the mean
+const PROPER_PAIR_STD_DEVS: f64 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mate {
+    // R1
+    First,
+    // R2
+    Second,
+}
+
+/*
+ * Distribution of insert sizes (the length of the sequenced fragment, from the start of one mate
+ * to the end of the other) of a paired-end library.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InsertSizeDistribution {
+    pub mean: f64,
+    pub std_dev: f64,
+}
+
+impl InsertSizeDistribution {
+    /*
+     * Estimates the distribution from the insert sizes of confidently mapped pairs. As in BWA,
+     * outliers (eg from chimeric fragments) further than 2 interquartile ranges outside the
+     * quartiles are ignored. Returns `None` if there are no insert sizes.
+     */
+    pub fn estimate(insert_sizes: &[usize]) -> Option<Self> {
+        if insert_sizes.is_empty() {
+            return None;
+        }
+
+        let mut sorted_sizes = insert_sizes.to_vec();
+        sorted_sizes.sort_unstable();
+
+        let lower_quartile = sorted_sizes[sorted_sizes.len() / 4] as f64;
+        let upper_quartile = sorted_sizes[sorted_sizes.len() * 3 / 4] as f64;
+        let interquartile_range = upper_quartile - lower_quartile;
+
+        let sizes: Vec<f64> = sorted_sizes
+            .into_iter()
+            .map(|size| size as f64)
+            .filter(|&size| {
+                size >= lower_quartile - 2.0 * interquartile_range
+                    && size <= upper_quartile + 2.0 * interquartile_range
+            })
+            .collect();
+
+        let mean = sizes.iter().sum::<f64>() / sizes.len() as f64;
+        let variance =
+            sizes.iter().map(|size| (size - mean).powi(2)).sum::<f64>() / sizes.len() as f64;
+
+        return Some(InsertSizeDistribution {
+            mean,
+            std_dev: variance.sqrt(),
+        });
+    }
+
+    // Allows at least a base either side of the mean, for libraries of a single fragment length
+    fn tolerance(&self) -> f64 {
+        return PROPER_PAIR_STD_DEVS * self.std_dev.max(1.0);
+    }
+
+    pub fn min_insert_size(&self) -> usize {
+        return (self.mean - self.tolerance()).max(0.0).ceil() as usize;
+    }
+
+    pub fn max_insert_size(&self) -> usize {
+        return (self.mean + self.tolerance()).floor() as usize;
+    }
+
+    pub fn contains(&self, insert_size: usize) -> bool {
+        return insert_size >= self.min_insert_size() && insert_size <= self.max_insert_size();
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PairedMapResult {
+    pub first: Option<MapReadResult>,
+    pub second: Option<MapReadResult>,
+    // Both mates mapped to the same contig, facing each other, with a plausible insert size
+    pub proper_pair: bool,
+    // Set if a mate which couldn't be seeded was found by aligning it near the other mate
+    pub rescued: bool,
+}
+
+impl PairedMapResult {
+    pub fn result(&self, mate: Mate) -> Option<&MapReadResult> {
+        match mate {
+            Mate::First => self.first.as_ref(),
+            Mate::Second => self.second.as_ref(),
+        }
+    }
+
+    /*
+     * Signed length of the template (TLEN in SAM), from the leftmost base of either mate to the
+     * rightmost. Positive for the leftmost mate and negative for the other, or 0 unless both mates
+     * mapped to the same contig.
+     */
+    pub fn template_length(&self, mate: Mate) -> isize {
+        let (Some(first), Some(second)) = (&self.first, &self.second) else {
+            return 0;
+        };
+
+        let template_length = template_length(first, second);
+        match mate {
+            Mate::First => template_length,
+            Mate::Second => -template_length,
+        }
+    }
+
+    // What the output formats need to know about the pair when writing `mate`
+    pub fn mate_info(&self, mate: Mate) -> MateInfo<'_> {
+        let other_mate = match mate {
+            Mate::First => Mate::Second,
+            Mate::Second => Mate::First,
+        };
+
+        return MateInfo {
+            mate,
+            mate_result: self.result(other_mate),
+            proper_pair: self.proper_pair,
+            template_length: self.template_length(mate),
+        };
+    }
+}
+
+// Describes the pair a read belongs to, for writing the read's SAM or BAM record
+#[derive(Debug, Clone, Copy)]
+pub struct MateInfo<'a> {
+    // Which mate the read is
+    pub mate: Mate,
+    // Where the read's mate mapped, if it did
+    pub mate_result: Option<&'a MapReadResult>,
+    pub proper_pair: bool,
+    pub template_length: isize,
+}
+
+impl ReadMappingIndex {
+    /*
+     * Estimates the insert size distribution of a library by mapping each mate of a sample of
+     * pairs on its own. Only pairs where both mates map confidently to the same contig, facing
+     * each other, are used. Returns `None` if there are no such pairs.
+     *
+     * The pairs are mapped in parallel, on the current rayon thread pool.
+     */
+    pub fn estimate_insert_sizes(
+        &self,
+        pairs: &[(FastqRecord, FastqRecord)],
+        options: &MapReadOptions,
+    ) -> Option<InsertSizeDistribution> {
+        let insert_sizes: Vec<usize> = pairs
+            .par_iter()
+            .filter(|(first, second)| min(first.seq.len(), second.seq.len()) >= options.seed_length)
+            .filter_map(|(first, second)| {
+                let first = self.map_read_hits(&first.seq, qualities(first), options);
+                let second = self.map_read_hits(&second.seq, qualities(second), options);
+                let (first, second) = (first.first()?, second.first()?);
+
+                let confident = min(first.mapq, second.mapq) >= MIN_ESTIMATION_MAPQ;
+                if !confident || !facing_each_other(first, second) {
+                    return None;
+                }
+                return Some(template_length(first, second).unsigned_abs());
+            })
+            .collect();
+
+        return InsertSizeDistribution::estimate(&insert_sizes);
+    }
+
+    /*
+     * Maps the mates of a pair together. Several hits are found for each mate, and the best
+     * scoring combination forming a proper pair is reported, so a mate in a repeat can be placed
+     * by the other mate. Otherwise each mate's best hit is reported. Only one hit is reported for
+     * each mate, whatever `options.max_hits` is.
+     *
+     * If only one mate maps, the other is searched for within `insert_sizes` of it (mate rescue),
+     * which finds mates with too many errors to be seeded.
+     */
+    pub fn map_pair(
+        &self,
+        first: &FastqRecord,
+        second: &FastqRecord,
+        options: &MapReadOptions,
+        insert_sizes: &InsertSizeDistribution,
+    ) -> PairedMapResult {
+        let mut hit_options = options.clone();
+        hit_options.max_hits = max(options.max_hits, PAIRING_HITS);
+        // Mates which aren't confidently placed on their own may be placed by the other mate
+        hit_options.min_mapq = 0;
+
+        // Mates shorter than a seed can only be found by rescue
+        let mate_hits = |record: &FastqRecord| match record.seq.len() < options.seed_length {
+            true => Vec::new(),
+            false => self.map_read_hits(&record.seq, qualities(record), &hit_options),
+        };
+        let first_hits = mate_hits(first);
+        let second_hits = mate_hits(second);
+
+        let mut paired_result = match best_proper_pair(first_hits, second_hits, insert_sizes) {
+            Ok(paired_result) => paired_result,
+            Err((first_hits, second_hits)) => {
+                let first_result = first_hits.into_iter().next();
+                let second_result = second_hits.into_iter().next();
+
+                match (first_result, second_result) {
+                    (Some(first_result), None) => {
+                        let second_result =
+                            self.rescue_mate(&first_result, &second.seq, options, insert_sizes);
+                        rescued_pair(Some(first_result), second_result, insert_sizes)
+                    }
+                    (None, Some(second_result)) => {
+                        let first_result =
+                            self.rescue_mate(&second_result, &first.seq, options, insert_sizes);
+                        rescued_pair(first_result, Some(second_result), insert_sizes)
+                    }
+                    (first_result, second_result) => PairedMapResult {
+                        proper_pair: false,
+                        rescued: false,
+                        first: first_result,
+                        second: second_result,
+                    },
+                }
+            }
+        };
+
+        // Mates are filtered by MAPQ only once the pair has been taken into account
+        paired_result.first = paired_result
+            .first
+            .filter(|result| result.mapq >= options.min_mapq);
+        paired_result.second = paired_result
+            .second
+            .filter(|result| result.mapq >= options.min_mapq);
+        if paired_result.first.is_none() || paired_result.second.is_none() {
+            paired_result.proper_pair = false;
+        }
+
+        return paired_result;
+    }
+
+    /*
+     * Aligns `read` to the region of the genome where its mate would be, given where `anchor`
+     * mapped. The mates of a proper pair face each other, so the read is on the opposite strand to
+     * `anchor`, and is within the longest plausible insert size of it.
+     */
+    fn rescue_mate(
+        &self,
+        anchor: &MapReadResult,
+        read: &str,
+        options: &MapReadOptions,
+        insert_sizes: &InsertSizeDistribution,
+    ) -> Option<MapReadResult> {
+        let contig_length = self.contigs()[anchor.contig_index].length;
+        let max_insert_size = insert_sizes.max_insert_size();
+
+        let (strand, window_start, window_end) = match anchor.strand {
+            Strand::Forward => (
+                Strand::Reverse,
+                anchor.position,
+                min(contig_length, anchor.position + max_insert_size),
+            ),
+            Strand::Reverse => {
+                let anchor_end = anchor.position + anchor.match_length;
+                (
+                    Strand::Forward,
+                    anchor_end.saturating_sub(max_insert_size),
+                    anchor_end,
+                )
+            }
+        };
+
+        let mut result = self.align_in_window(
+            read,
+            strand,
+            anchor.contig_index,
+            window_start,
+            window_end,
+            &options.scoring,
+        )?;
+
+        // The rescued mate can't be placed more confidently than the mate it was found from
+        result.mapq = min(result.mapq, anchor.mapq);
+
+        return Some(result);
+    }
+}
+
+/*
+ * Finds the highest scoring combination of hits forming a proper pair. The earliest (ie best)
+ * hits are preferred when several combinations score equally well. Returns the hits unchanged if
+ * there is no proper pair.
+ */
+fn best_proper_pair(
+    first_hits: Vec<MapReadResult>,
+    second_hits: Vec<MapReadResult>,
+    insert_sizes: &InsertSizeDistribution,
+) -> Result<PairedMapResult, (Vec<MapReadResult>, Vec<MapReadResult>)> {
+    let mut proper_pairs = Vec::new();
+    for (first_index, first_hit) in first_hits.iter().enumerate() {
+        for (second_index, second_hit) in second_hits.iter().enumerate() {
+            if is_proper_pair(first_hit, second_hit, insert_sizes) {
+                let score = first_hit.alignment_score + second_hit.alignment_score;
+                proper_pairs.push((score, first_index, second_index));
+            }
+        }
+    }
+
+    // Stable, so the earliest of equally good pairs stays first
+    proper_pairs.sort_by_key(|&(score, _, _)| std::cmp::Reverse(score));
+
+    let Some(&(best_score, first_index, second_index)) = proper_pairs.first() else {
+        return Err((first_hits, second_hits));
+    };
+    let multi_mapped = proper_pairs
+        .get(1)
+        .is_some_and(|&(score, _, _)| score == best_score);
+
+    /*
+     * Unless other pairs are as good, the pair is as confidently placed as its most confident
+     * mate, since the other mate must be nearby. Hits other than the best for a mate have no
+     * confidence of their own.
+     */
+    let mapq = |hits: &[MapReadResult], index: usize| match index {
+        0 => hits[0].mapq,
+        _ => 0,
+    };
+    let pair_mapq = match multi_mapped {
+        true => 0,
+        false => max(
+            mapq(&first_hits, first_index),
+            mapq(&second_hits, second_index),
+        ),
+    };
+
+    let mut first_result = first_hits.into_iter().nth(first_index).unwrap();
+    let mut second_result = second_hits.into_iter().nth(second_index).unwrap();
+    for result in [&mut first_result, &mut second_result] {
+        result.mapq = pair_mapq;
+        result.secondary = false;
+        result.multi_mapped = multi_mapped;
+    }
+
+    return Ok(PairedMapResult {
+        first: Some(first_result),
+        second: Some(second_result),
+        proper_pair: true,
+        rescued: false,
+    });
+}
+
+fn rescued_pair(
+    first: Option<MapReadResult>,
+    second: Option<MapReadResult>,
+    insert_sizes: &InsertSizeDistribution,
+) -> PairedMapResult {
+    let proper_pair = match (&first, &second) {
+        (Some(first), Some(second)) => is_proper_pair(first, second, insert_sizes),
+        _ => false,
+    };
+
+    return PairedMapResult {
+        rescued: first.is_some() && second.is_some(),
+        first,
+        second,
+        proper_pair,
+    };
+}
+
+// On the same contig and opposite strands, with the forwards mate not starting after the reverse one
+fn facing_each_other(first: &MapReadResult, second: &MapReadResult) -> bool {
+    if first.contig_index != second.contig_index || first.strand == second.strand {
+        return false;
+    }
+
+    let (forward, reverse) = match first.strand {
+        Strand::Forward => (first, second),
+        Strand::Reverse => (second, first),
+    };
+
+    return forward.position <= reverse.position + reverse.match_length;
+}
+
+fn is_proper_pair(
+    first: &MapReadResult,
+    second: &MapReadResult,
+    insert_sizes: &InsertSizeDistribution,
+) -> bool {
+    return facing_each_other(first, second)
+        && insert_sizes.contains(template_length(first, second).unsigned_abs());
+}
+
+// TLEN of the first mate, which is positive if it is the leftmost mate
+fn template_length(first: &MapReadResult, second: &MapReadResult) -> isize {
+    if first.contig_index != second.contig_index {
+        return 0;
+    }
+
+    let start = min(first.position, second.position);
+    let end = max(
+        first.position + first.match_length,
+        second.position + second.match_length,
+    );
+    let template_length = (end - start) as isize;
+
+    if first.position <= second.position {
+        return template_length;
+    }
+    return -template_length;
+}
+
+// Qualities to choose seeds with, if the record has them
+fn qualities(record: &FastqRecord) -> Option<&str> {
+    if record.qual.is_empty() {
+        return None;
+    }
+    return Some(&record.qual);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fasta_parsing::FastaRecord;
+    use crate::read_mapping_index::reverse_complement;
+
+    const GENOME: &str = "TGTACGGTCGTTCTCCAGGCGCGGTTTCGATCGGTCCACGTAAACTTAGAAGGCTCGACAATGCGAGAGGTCACCAAATACACGCAGTCCTCTTCTCTAAGGCATGCGTTGATCCCCAAACGGTGCGATCCGTGCTGACAATACCCGCTACATCGGGTGCCCATGATCTACCAGTCAGCTCCATACGGTTAGCGC";
+
+    fn fastq_record(seq: &str) -> FastqRecord {
+        return FastqRecord {
+            name: String::from("pair"),
+            comment: None,
+            seq: String::from(seq),
+            qual: String::new(),
+        };
+    }
+
+    // Mates from either end of a fragment of `GENOME`, sequenced facing each other
+    fn fragment_pair(start: usize, end: usize, mate_length: usize) -> (FastqRecord, FastqRecord) {
+        return (
+            fastq_record(&GENOME[start..start + mate_length]),
+            fastq_record(&reverse_complement(&GENOME[end - mate_length..end])),
+        );
+    }
+
+    #[test]
+    fn estimate_insert_sizes() {
+        let insert_sizes = InsertSizeDistribution::estimate(&[98, 99, 100, 100, 101, 102, 5000]);
+        let insert_sizes = insert_sizes.unwrap();
+
+        // The chimeric fragment is ignored
+        assert_eq!(insert_sizes.mean, 100.0);
+        assert!((insert_sizes.std_dev - 1.29).abs() < 0.01);
+        assert!(insert_sizes.contains(95));
+        assert!(!insert_sizes.contains(110));
+
+        assert_eq!(InsertSizeDistribution::estimate(&[]), None);
+
+        let read_mapping_index =
+            ReadMappingIndex::new(&[FastaRecord::new("genome", GENOME)]).unwrap();
+        let options = MapReadOptions::new(15, 2);
+        let pairs: Vec<_> = (0..50)
+            .map(|start| fragment_pair(start, start + 120 + start % 5, 25))
+            .collect();
+
+        let insert_sizes = read_mapping_index.estimate_insert_sizes(&pairs, &options);
+        assert_eq!(insert_sizes.unwrap().mean, 122.0);
+    }
+
+    #[test]
+    fn map_pair() {
+        let read_mapping_index =
+            ReadMappingIndex::new(&[FastaRecord::new("genome", GENOME)]).unwrap();
+        let options = MapReadOptions::new(15, 2);
+        let insert_sizes = InsertSizeDistribution {
+            mean: 100.0,
+            std_dev: 5.0,
+        };
+
+        let (first, second) = fragment_pair(20, 120, 25);
+        let paired_result = read_mapping_index.map_pair(&first, &second, &options, &insert_sizes);
+        assert!(paired_result.proper_pair);
+        assert!(!paired_result.rescued);
+        assert_eq!(paired_result.first.as_ref().unwrap().position, 20);
+        assert_eq!(paired_result.second.as_ref().unwrap().position, 95);
+        assert_eq!(
+            paired_result.second.as_ref().unwrap().strand,
+            Strand::Reverse
+        );
+        assert_eq!(paired_result.template_length(Mate::First), 100);
+        assert_eq!(paired_result.template_length(Mate::Second), -100);
+
+        // Too far apart for the library
+        let (first, second) = fragment_pair(20, 180, 25);
+        let paired_result = read_mapping_index.map_pair(&first, &second, &options, &insert_sizes);
+        assert!(!paired_result.proper_pair);
+        assert_eq!(paired_result.template_length(Mate::First), 160);
+    }
+
+    #[test]
+    fn map_pair_short_mate() {
+        let read_mapping_index =
+            ReadMappingIndex::new(&[FastaRecord::new("genome", GENOME)]).unwrap();
+        let options = MapReadOptions::new(15, 2);
+        let insert_sizes = InsertSizeDistribution {
+            mean: 100.0,
+            std_dev: 5.0,
+        };
+
+        // The second mate is shorter than a seed, so can't be mapped on its own
+        let (first, _) = fragment_pair(20, 120, 25);
+        let (_, second) = fragment_pair(20, 120, 10);
+        assert_eq!(
+            read_mapping_index.estimate_insert_sizes(&[(first.clone(), second.clone())], &options),
+            None
+        );
+
+        let paired_result = read_mapping_index.map_pair(&first, &second, &options, &insert_sizes);
+        assert_eq!(paired_result.first.as_ref().unwrap().position, 20);
+        let second_result = paired_result.second.unwrap();
+        assert!(paired_result.rescued);
+        assert_eq!(second_result.position, 110);
+        assert_eq!(second_result.strand, Strand::Reverse);
+    }
+
+    #[test]
+    fn map_pair_rescues_mate() {
+        let read_mapping_index =
+            ReadMappingIndex::new(&[FastaRecord::new("genome", GENOME)]).unwrap();
+        let options = MapReadOptions::new(15, 2);
+        let insert_sizes = InsertSizeDistribution {
+            mean: 100.0,
+            std_dev: 5.0,
+        };
+
+        // Errors every 10 bases, so no seed of the second mate can be found
+        let (first, mut second) = fragment_pair(20, 120, 30);
+        second.seq = second
+            .seq
+            .char_indices()
+            .map(|(index, nuc)| match (index % 10, nuc) {
+                (5, 'A') => 'C',
+                (5, _) => 'A',
+                _ => nuc,
+            })
+            .collect();
+        assert_eq!(read_mapping_index.map_read(&second.seq, 15, 2), None);
+
+        let paired_result = read_mapping_index.map_pair(&first, &second, &options, &insert_sizes);
+        assert!(paired_result.proper_pair);
+        assert!(paired_result.rescued);
+
+        let second_result = paired_result.second.unwrap();
+        assert_eq!(second_result.strand, Strand::Reverse);
+        // The bases before the error at 94 are soft clipped
+        assert_eq!(second_result.position, 95);
+        assert_eq!(second_result.edit_distance, 2);
+    }
+}
@@ -0,0 +1,183 @@
+use std::thread;
+
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+
+// Reads mapped per batch, enough to keep every thread busy without holding much of the FASTQ
+pub const DEFAULT_BATCH_SIZE: usize = 10_000;
+
+/*
+ * Maps reads on a pool of threads sharing one `ReadMappingIndex`, while keeping the output in the
+ * same order as the input.
+ *
+ * Reads are taken from the input in batches. Each batch is mapped in parallel while the results
+ * of the previous batch are written, so at most 2 batches are held in memory however large the
+ * input is.
+ */
+pub struct ParallelMapper {
+    pool: ThreadPool,
+    batch_size: usize,
+}
+
+impl ParallelMapper {
+    // A `thread_count` of 0 uses a thread per CPU core
+    pub fn new(thread_count: usize, batch_size: usize) -> Result<Self, ThreadPoolBuildError> {
+        assert!(batch_size > 0);
+
+        let pool = ThreadPoolBuilder::new().num_threads(thread_count).build()?;
+        return Ok(ParallelMapper { pool, batch_size });
+    }
+
+    pub fn thread_count(&self) -> usize {
+        return self.pool.current_num_threads();
+    }
+
+    // Calls `op` on the pool, so any parallel iterators it uses run on the pool's threads
+    pub fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        return self.pool.install(op);
+    }
+
+    /*
+     * Calls `map` on each item (eg a read, or the mates of a pair) in parallel, then `write` with
+     * each item and its result in the order the items were read. Stops at the first error from
+     * the input or from `write`. Items before an input error are still mapped and written, as if
+     * they were mapped one at a time.
+     */
+    pub fn run<T, R, E, M, W>(
+        &self,
+        items: impl Iterator<Item = Result<T, E>>,
+        map: M,
+        mut write: W,
+    ) -> Result<(), E>
+    where
+        T: Send,
+        R: Send,
+        M: Fn(&T) -> R + Sync,
+        W: FnMut(T, R) -> Result<(), E>,
+    {
+        let mut items = items.peekable();
+        let mut previous_batch: Vec<(T, R)> = Vec::new();
+        let mut input_error = None;
+
+        loop {
+            let mut batch = Vec::with_capacity(self.batch_size);
+            for item in items.by_ref().take(self.batch_size) {
+                match item {
+                    Ok(item) => batch.push(item),
+                    Err(error) => {
+                        input_error = Some(error);
+                        break;
+                    }
+                }
+            }
+
+            // Map this batch on the pool while writing the previous batch on this thread
+            let (mapped_batch, write_result) = thread::scope(|scope| {
+                let mapping = scope.spawn(|| {
+                    return self.pool.install(|| {
+                        return batch
+                            .into_par_iter()
+                            .map(|item| {
+                                let result = map(&item);
+                                return (item, result);
+                            })
+                            .collect::<Vec<_>>();
+                    });
+                });
+
+                let write_result = previous_batch
+                    .drain(..)
+                    .try_for_each(|(item, result)| write(item, result));
+
+                let mapped_batch = mapping
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                return (mapped_batch, write_result);
+            });
+
+            write_result?;
+            previous_batch = mapped_batch;
+
+            if input_error.is_some() || items.peek().is_none() {
+                break;
+            }
+        }
+
+        previous_batch
+            .into_iter()
+            .try_for_each(|(item, result)| write(item, result))?;
+
+        return match input_error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fasta_parsing::FastaRecord;
+    use crate::read_mapping_index::{MapReadOptions, ReadMappingIndex};
+
+    #[test]
+    fn mapped_in_input_order() {
+        let genome = "ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT";
+        let read_mapping_index =
+            ReadMappingIndex::new(&[FastaRecord::new("genome", genome)]).unwrap();
+        let options = MapReadOptions::new(8, 1);
+
+        // Batches smaller than the number of reads, and a last batch which isn't full
+        let parallel_mapper = ParallelMapper::new(4, 7).unwrap();
+        assert_eq!(parallel_mapper.thread_count(), 4);
+
+        let reads: Vec<String> = (0..genome.len() - 10)
+            .map(|start| genome[start..start + 10].to_string())
+            .collect();
+
+        let mut results = Vec::new();
+        parallel_mapper
+            .run(
+                reads.iter().cloned().map(Ok::<_, ()>),
+                |read| read_mapping_index.map_read_with_options(read, &options),
+                |read, result| {
+                    results.push((read, result));
+                    return Ok(());
+                },
+            )
+            .unwrap();
+
+        let expected: Vec<_> = reads
+            .into_iter()
+            .map(|read| {
+                let result = read_mapping_index.map_read_with_options(&read, &options);
+                return (read, result);
+            })
+            .collect();
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn stops_at_first_error() {
+        let parallel_mapper = ParallelMapper::new(2, 3).unwrap();
+
+        let items = (0..10).map(|item| match item {
+            5 => Err(item),
+            _ => Ok(item),
+        });
+
+        let mut written = Vec::new();
+        let result = parallel_mapper.run(
+            items,
+            |item| item * 2,
+            |_, result| {
+                written.push(result);
+                return Ok(());
+            },
+        );
+
+        assert_eq!(result, Err(5));
+        // Items in the batch containing the error are written up to the error
+        assert_eq!(written, vec![0, 2, 4, 6, 8]);
+    }
+}
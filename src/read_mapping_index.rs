@@ -1,188 +1,1481 @@
-use bincode::decode_from_std_read;
-use bincode::{config::standard, encode_into_std_write, error::EncodeError, Decode, Encode};
+use bincode::{Decode, Encode};
 
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::{cmp::min, collections::HashMap};
+use std::io::{self, BufWriter, Write};
+use std::{
+    cmp::{max, min},
+    collections::HashMap,
+};
 
-use crate::fm_index::FMIndex;
+use crate::alignment::{banded_local_alignment, AlignmentScoring};
+use crate::cigar::{Cigar, CigarOp};
+use crate::fasta_parsing::FastaRecord;
+use crate::fm_index::{FMIndex, FMIndexMemoryUsage, MATCH_SCORE, MISMATCH_PENALTY, NON_NUCLEOTIDE};
+use crate::index_file::{
+    genome_checksum, BuildParams, BwtStorage, IndexFileError, IndexHeader, IndexReader,
+    IndexWriter, SamplingSteps, WordLayout,
+};
+use crate::mapping_quality::mapping_quality;
+use crate::packed_bwt::BASES_PER_WORD;
+use crate::splicing::{Junction, SpliceMotif, SpliceOptions};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Strand {
+    Forward,
+    Reverse,
+}
 
 #[derive(Debug, PartialEq)]
 pub struct MapReadResult {
-    pub genome_position: usize,
+    // Index into `ReadMappingIndex::contigs` of the contig containing the match
+    pub contig_index: usize,
+    // Leftmost position of the match in the forwards contig, regardless of strand
+    pub position: usize,
+    // Number of genome bases covered by the match
     pub match_length: usize,
+    // Part of the read covered by the match, as offsets into the read as given (even if the
+    // reverse complement matched). Bases outside it are soft clipped in the CIGAR
+    pub read_start: usize,
+    // Exclusive
+    pub read_end: usize,
     pub seed_attempt: usize,
+    // Phred-scaled confidence that the read is placed correctly, as used by SAM
+    pub mapq: u8,
+    // Set for every hit other than the best when several hits are reported for the read
+    pub secondary: bool,
+    // Set when the read has another hit scoring as well as the best, so its placement is arbitrary
+    pub multi_mapped: bool,
+    // `Strand::Reverse` if the reverse complement of the read matched the genome
+    pub strand: Strand,
+    // Offsets from `position` of each mismatch within the match
+    pub mismatch_positions: Vec<usize>,
+    pub alignment_score: isize,
+    // Describes the whole read in the forwards genome's orientation, soft clipping unmatched bases
+    pub cigar: Cigar,
+    // Number of mismatches, inserted bases and deleted bases in the match
+    pub edit_distance: usize,
+    // Introns skipped by a spliced read, in the same order as the `N` operations in the CIGAR
+    pub junctions: Vec<Junction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtensionMode {
+    // Extend seeds one base at a time by walking the FM indexes, tolerating only mismatches
+    Naive,
+    // Align the whole read around each seed hit with banded Smith-Waterman, tolerating indels
+    BandedAlignment { band_width: usize },
+}
+
+// How to choose the primary hit among hits with the same score
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TieBreak {
+    // The leftmost hit in the genome, then the forwards strand
+    Leftmost,
+    /*
+     * A pseudo-random hit, chosen from the seed and the read's sequence. The same read always
+     * gets the same hit for a given seed, regardless of the order reads are mapped in.
+     */
+    Random { seed: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct MapReadOptions {
+    pub seed_length: usize,
+    pub max_seeds: usize,
+    /*
+     * Number of hits to report for each read, best first. `usize::MAX` reports every hit. Hits
+     * all come from the first seed which maps, so copies of the read's other seeds aren't included.
+     * A read placed by chaining seed hits across introns only has its primary hit reported
+     */
+    pub max_hits: usize,
+    // Reads with a lower mapping quality are reported as unmapped
+    pub min_mapq: u8,
+    // Seeds containing a base with a lower Phred quality are skipped, when qualities are known
+    pub min_seed_quality: u8,
+    // Maximum number of mismatches allowed when extending a seed
+    pub max_mismatches: usize,
+    // Stop extending once the score drops this far below the best score seen (X-drop)
+    pub x_drop: usize,
+    pub extension_mode: ExtensionMode,
+    // Only used by `ExtensionMode::BandedAlignment`
+    pub scoring: AlignmentScoring,
+    // Chain seed hits across introns when the read does not match end to end
+    pub splicing: Option<SpliceOptions>,
+    pub tie_break: TieBreak,
+}
+
+impl MapReadOptions {
+    pub fn new(seed_length: usize, max_seeds: usize) -> Self {
+        assert!(seed_length > 0);
+
+        return MapReadOptions {
+            seed_length,
+            max_seeds,
+            max_hits: 1,
+            min_mapq: 0,
+            min_seed_quality: 10,
+            max_mismatches: 0,
+            x_drop: 20,
+            extension_mode: ExtensionMode::Naive,
+            scoring: AlignmentScoring::default(),
+            splicing: None,
+            tie_break: TieBreak::Leftmost,
+        };
+    }
+}
+
+// A match in the forwards genome found from a seed
+struct SeedMatch {
+    genome_position: usize,
+    match_length: usize,
+    // Part of the read covered by the match, in the orientation the read was matched in
+    read_start: usize,
+    read_end: usize,
+    mismatch_positions: Vec<usize>,
+    score: isize,
+    cigar: Cigar,
+    edit_distance: usize,
+    junctions: Vec<Junction>,
+}
+
+impl SeedMatch {
+    fn into_map_read_result(
+        self,
+        contigs: &[Contig],
+        seed_attempt: usize,
+        strand: Strand,
+        read_length: usize,
+        mapq: u8,
+    ) -> MapReadResult {
+        let (contig_index, position) = locate_in_contigs(contigs, self.genome_position);
+
+        // The start of the read is at the end of its reverse complement
+        let (read_start, read_end) = match strand {
+            Strand::Forward => (self.read_start, self.read_end),
+            Strand::Reverse => (read_length - self.read_end, read_length - self.read_start),
+        };
+
+        return MapReadResult {
+            contig_index,
+            position,
+            match_length: self.match_length,
+            read_start,
+            read_end,
+            seed_attempt,
+            mapq,
+            secondary: false,
+            multi_mapped: false,
+            strand,
+            mismatch_positions: self.mismatch_positions,
+            alignment_score: self.score,
+            cigar: self.cigar,
+            edit_distance: self.edit_distance,
+            junctions: self.junctions,
+        };
+    }
+}
+
+// A match without indels found by extending a seed through the FM indexes
+struct UngappedMatch {
+    genome_position: usize,
+    // Offset into the read where the match begins
+    read_start: usize,
+    match_length: usize,
+    // Offsets from `genome_position` of each mismatch within the match
+    mismatch_positions: Vec<usize>,
+}
+
+impl UngappedMatch {
+    fn read_end(&self) -> usize {
+        return self.read_start + self.match_length;
+    }
+
+    // Offset between positions in the genome and the read along the match
+    fn diagonal(&self) -> isize {
+        return self.genome_position as isize - self.read_start as isize;
+    }
+
+    fn score(&self) -> isize {
+        let mismatch_count = self.mismatch_positions.len();
+        let match_count = self.match_length - mismatch_count;
+        return match_count as isize * MATCH_SCORE - mismatch_count as isize * MISMATCH_PENALTY;
+    }
+
+    fn into_seed_match(self, read_length: usize) -> SeedMatch {
+        let mut cigar = Cigar::new();
+        cigar.push(CigarOp::SoftClip, self.read_start);
+        cigar.push(CigarOp::Match, self.match_length);
+        cigar.push(
+            CigarOp::SoftClip,
+            read_length - self.read_start - self.match_length,
+        );
+
+        return SeedMatch {
+            genome_position: self.genome_position,
+            match_length: self.match_length,
+            read_start: self.read_start,
+            read_end: self.read_end(),
+            score: self.score(),
+            cigar,
+            edit_distance: self.mismatch_positions.len(),
+            mismatch_positions: self.mismatch_positions,
+            junctions: Vec::new(),
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct Contig {
+    pub name: String,
+    pub length: usize,
+    // Position of the start of the contig in the concatenated genome
+    pub offset: usize,
+}
+
+// A run of a single ambiguous IUPAC code (eg N or R) in a contig, masked out of the index
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct AmbiguousSpan {
+    pub contig_index: usize,
+    // Position of the first base of the run within the contig
+    pub start: usize,
+    pub length: usize,
+    pub code: char,
 }
 
-#[derive(Encode, Decode)]
 pub struct ReadMappingIndex {
     forwards_fm_index: FMIndex,
     reverse_fm_index: FMIndex,
     genome_length: usize,
+    contigs: Vec<Contig>,
+    ambiguous_spans: Vec<AmbiguousSpan>,
+    build_params: BuildParams,
+    genome_checksum: u32,
 }
 
-impl ReadMappingIndex {
-    pub fn new(genome: &str) -> Self {
-        // TODO: Not nice having to reallocate `str` just to add the sentinel
-        let mut forwards_genome = String::from(genome);
+// Bytes used by each FM index of a `ReadMappingIndex`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexMemoryUsage {
+    pub forwards: FMIndexMemoryUsage,
+    pub reverse: FMIndexMemoryUsage,
+}
+
+impl IndexMemoryUsage {
+    pub fn total(&self) -> usize {
+        return self.forwards.total() + self.reverse.total();
+    }
+}
+
+// Why a `ReadMappingIndex` couldn't be built from a set of FASTA records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexBuildError {
+    // There are no records, so no genome to index
+    NoContigs,
+    // A contig contains a character which isn't an IUPAC nucleotide code, eg `*` or a digit
+    InvalidBase {
+        contig_name: String,
+        // Position of the character within the contig
+        position: usize,
+        base: char,
+    },
+    // A suffix array sampling step of 0
+    InvalidSuffixArrayStep {
+        step: usize,
+    },
+    // A rank sampling step of 0, or one `BwtStorage::Packed` can't use, not being a multiple of 32
+    InvalidRankStep {
+        step: usize,
+    },
+}
+
+impl fmt::Display for IndexBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexBuildError::NoContigs => return write!(f, "no sequences to index"),
+            IndexBuildError::InvalidBase {
+                contig_name,
+                position,
+                base,
+            } => {
+                return write!(
+                    f,
+                    "contig {contig_name} has {base:?} at position {position}, which isn't an \
+                     IUPAC nucleotide code"
+                )
+            }
+            IndexBuildError::InvalidSuffixArrayStep { step } => {
+                return write!(f, "suffix array sampling step {step} is not positive")
+            }
+            IndexBuildError::InvalidRankStep { step: 0 } => {
+                return write!(f, "rank sampling step 0 is not positive")
+            }
+            IndexBuildError::InvalidRankStep { step } => {
+                return write!(
+                    f,
+                    "rank sampling step {step} is not a multiple of {BASES_PER_WORD}, as the \
+                     packed BWT requires"
+                )
+            }
+        }
+    }
+}
+
+impl Error for IndexBuildError {}
+
+/*
+ * Builds a `ReadMappingIndex`, choosing how densely each FM index is sampled. Seeds are looked
+ * up and extended in both indexes, so both sampling steps affect the mapping speed.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct ReadMappingIndexBuilder {
+    build_params: BuildParams,
+}
+
+impl ReadMappingIndexBuilder {
+    pub fn new() -> Self {
+        return ReadMappingIndexBuilder::default();
+    }
+
+    // Sets the sampling steps of both FM indexes
+    pub fn sampling_steps(self, sampling_steps: SamplingSteps) -> Self {
+        return self
+            .forwards_sampling_steps(sampling_steps)
+            .reverse_sampling_steps(sampling_steps);
+    }
+
+    pub fn forwards_sampling_steps(mut self, sampling_steps: SamplingSteps) -> Self {
+        self.build_params.forwards = sampling_steps;
+        return self;
+    }
+
+    pub fn reverse_sampling_steps(mut self, sampling_steps: SamplingSteps) -> Self {
+        self.build_params.reverse = sampling_steps;
+        return self;
+    }
+
+    // `BwtStorage::Packed` requires rank sampling steps which are multiples of 32, or `build` fails
+    pub fn bwt_storage(mut self, bwt_storage: BwtStorage) -> Self {
+        self.build_params.bwt_storage = bwt_storage;
+        return self;
+    }
+
+    // Indexes the concatenation of every record, keeping track of where each contig lies within it
+    pub fn build(&self, records: &[FastaRecord]) -> Result<ReadMappingIndex, IndexBuildError> {
+        // Checked before anything is built, as the setters can be called in any order
+        for sampling_steps in [self.build_params.forwards, self.build_params.reverse] {
+            if sampling_steps.suffix_array == 0 {
+                return Err(IndexBuildError::InvalidSuffixArrayStep {
+                    step: sampling_steps.suffix_array,
+                });
+            }
+
+            let packed = self.build_params.bwt_storage == BwtStorage::Packed;
+            if sampling_steps.rank == 0
+                || (packed && !sampling_steps.rank.is_multiple_of(BASES_PER_WORD))
+            {
+                return Err(IndexBuildError::InvalidRankStep {
+                    step: sampling_steps.rank,
+                });
+            }
+        }
+
+        if records.is_empty() {
+            return Err(IndexBuildError::NoContigs);
+        }
+
+        let build_params = self.build_params;
+        let mut contigs = Vec::with_capacity(records.len());
+        let mut ambiguous_spans = Vec::new();
+        let mut genome = String::new();
+
+        for (contig_index, record) in records.iter().enumerate() {
+            /*
+             * Separate contigs so that seeds and extensions can't run from one contig into the
+             * next, which would produce chimeric matches
+             */
+            if !genome.is_empty() {
+                genome.push(NON_NUCLEOTIDE);
+            }
+
+            contigs.push(Contig {
+                name: record.name.clone(),
+                length: record.sequence.len(),
+                offset: genome.len(),
+            });
+            genome.push_str(&mask_ambiguous_bases(
+                record,
+                contig_index,
+                &mut ambiguous_spans,
+            )?);
+        }
+
+        let mut forwards_genome = genome.clone();
         forwards_genome.push('$');
 
-        let forwards_fm_index = FMIndex::new(&forwards_genome, 128, 128);
+        let forwards_fm_index = FMIndex::with_bwt_storage(
+            &forwards_genome,
+            build_params.forwards.suffix_array,
+            build_params.forwards.rank,
+            build_params.bwt_storage,
+        );
 
         let mut reversed_genome: String = genome.chars().rev().collect();
         reversed_genome.push('$');
 
-        let reverse_fm_index = FMIndex::new(&reversed_genome, 128, 128);
+        let reverse_fm_index = FMIndex::with_bwt_storage(
+            &reversed_genome,
+            build_params.reverse.suffix_array,
+            build_params.reverse.rank,
+            build_params.bwt_storage,
+        );
 
-        return ReadMappingIndex {
+        return Ok(ReadMappingIndex {
             forwards_fm_index,
             reverse_fm_index,
             genome_length: genome.len() + 1, // +1 for sentinel
+            contigs,
+            ambiguous_spans,
+            build_params,
+            genome_checksum: genome_checksum(&genome),
+        });
+    }
+}
+
+impl ReadMappingIndex {
+    // Indexes the records with the default sampling steps
+    pub fn new(records: &[FastaRecord]) -> Result<Self, IndexBuildError> {
+        return ReadMappingIndexBuilder::new().build(records);
+    }
+
+    pub fn contigs(&self) -> &[Contig] {
+        return &self.contigs;
+    }
+
+    pub fn ambiguous_spans(&self) -> &[AmbiguousSpan] {
+        return &self.ambiguous_spans;
+    }
+
+    // Number of bases in the genome which were ambiguous, and so can never be matched
+    pub fn masked_base_count(&self) -> usize {
+        return self.ambiguous_spans.iter().map(|span| span.length).sum();
+    }
+
+    pub fn build_params(&self) -> BuildParams {
+        return self.build_params;
+    }
+
+    pub fn memory_usage(&self) -> IndexMemoryUsage {
+        return IndexMemoryUsage {
+            forwards: self.forwards_fm_index.memory_usage(),
+            reverse: self.reverse_fm_index.memory_usage(),
+        };
+    }
+
+    // CRC-32 of the indexed genome, recorded when the index was built
+    pub fn genome_checksum(&self) -> u32 {
+        return self.genome_checksum;
+    }
+
+    pub fn header(&self) -> IndexHeader {
+        return IndexHeader {
+            word_layout: WordLayout::native(),
+            build_params: self.build_params,
+            genome_length: self.genome_length,
+            genome_checksum: self.genome_checksum,
+            contigs: self.contigs.clone(),
+            ambiguous_spans: self.ambiguous_spans.clone(),
         };
     }
 
-    pub fn to_file(&self, filename: &str) -> Result<usize, EncodeError> {
-        let mut file = File::create(filename).unwrap();
-        encode_into_std_write(self, &mut file, standard())
-    }
+    // Saves the index with a header describing it, in the layout described in `index_file`
+    pub fn to_file(&self, filename: &str) -> io::Result<()> {
+        let mut writer = IndexWriter::new(BufWriter::new(File::create(filename)?));
+        writer.write_header(&self.header())?;
+        self.forwards_fm_index.write_to(&mut writer)?;
+        self.reverse_fm_index.write_to(&mut writer)?;
+
+        return writer.into_inner().flush();
+    }
+
+    /*
+     * Memory maps the index rather than reading it, so the FM indexes are used in place without
+     * being copied. Pages are read from disk as they're first used, and are shared with any
+     * other process using the same index. The file must not be modified while it's loaded.
+     */
+    pub fn from_file(filename: &str) -> Result<Self, IndexFileError> {
+        let (header, mut reader) = IndexReader::open(filename)?;
+        let forwards_fm_index = FMIndex::read_from(&mut reader)?;
+        let reverse_fm_index = FMIndex::read_from(&mut reader)?;
+        reader.finish()?;
+
+        if forwards_fm_index.seq_len() != header.genome_length
+            || reverse_fm_index.seq_len() != header.genome_length
+        {
+            return Err(IndexFileError::Corrupt(
+                "FM index length doesn't match the header".to_string(),
+            ));
+        }
+        if forwards_fm_index.sampling_steps() != header.build_params.forwards
+            || reverse_fm_index.sampling_steps() != header.build_params.reverse
+            || forwards_fm_index.bwt_storage() != header.build_params.bwt_storage
+            || reverse_fm_index.bwt_storage() != header.build_params.bwt_storage
+        {
+            return Err(IndexFileError::Corrupt(
+                "FM index sampling steps or BWT storage don't match the header".to_string(),
+            ));
+        }
+
+        return Ok(ReadMappingIndex {
+            forwards_fm_index,
+            reverse_fm_index,
+            genome_length: header.genome_length,
+            contigs: header.contigs,
+            ambiguous_spans: header.ambiguous_spans,
+            build_params: header.build_params,
+            genome_checksum: header.genome_checksum,
+        });
+    }
+
+    /*
+     * Reads the whole genome back out of the index and compares it with the checksum in the
+     * header, to catch corruption which still decodes
+     */
+    pub fn verify(&self) -> Result<(), IndexFileError> {
+        let genome = self.forwards_fm_index.extract(0, self.genome_length - 1);
+
+        let found = genome_checksum(&genome);
+        if found != self.genome_checksum {
+            return Err(IndexFileError::ChecksumMismatch {
+                expected: self.genome_checksum,
+                found,
+            });
+        }
+        return Ok(());
+    }
+
+    pub fn map_read(
+        &self,
+        read: &str,
+        seed_length: usize,
+        max_seeds: usize,
+    ) -> Option<MapReadResult> {
+        return self.map_read_with_options(read, &MapReadOptions::new(seed_length, max_seeds));
+    }
+
+    // Maps a read to its best hit, if any
+    pub fn map_read_with_options(
+        &self,
+        read: &str,
+        options: &MapReadOptions,
+    ) -> Option<MapReadResult> {
+        return self.map_read_hits(read, None, options).into_iter().next();
+    }
+
+    /*
+     * Maps a read using its Phred+33 base qualities (as found in FASTQ files) to choose seeds.
+     * Seeds are placed in the highest quality parts of the read first, so fewer seeds are wasted
+     * on sequencing errors.
+     */
+    pub fn map_read_with_qualities(
+        &self,
+        read: &str,
+        qualities: &str,
+        options: &MapReadOptions,
+    ) -> Option<MapReadResult> {
+        return self
+            .map_read_hits(read, Some(qualities), options)
+            .into_iter()
+            .next();
+    }
+
+    /*
+     * Maps a read to up to `options.max_hits` hits, best first. The first hit is the primary
+     * alignment and the rest are marked as secondary. The hits are the occurrences of the first
+     * seed which maps (as for the primary alignment), not merged across seeds, and a spliced
+     * primary alignment has no secondary hits. Qualities are used to choose seeds if given.
+     */
+    pub fn map_read_hits(
+        &self,
+        read: &str,
+        qualities: Option<&str>,
+        options: &MapReadOptions,
+    ) -> Vec<MapReadResult> {
+        assert!(read.len() >= options.seed_length);
+
+        let seed_start_indices = match qualities {
+            Some(qualities) => {
+                assert_eq!(read.len(), qualities.len());
+                quality_seed_start_indices(
+                    read,
+                    qualities,
+                    options.seed_length,
+                    options.min_seed_quality,
+                )
+            }
+            /*
+             * Seed bounds in the forward direction
+             * We start by choosing seeds from the beginning of the reads since
+             * reads are typically more accurate at the beginning
+             */
+            None => seed_start_indices(read, options.seed_length),
+        };
+
+        return self.map_read_with_seeds(read, &seed_start_indices, options);
+    }
+
+    // Tries each seed in turn until one maps, up to `options.max_seeds`
+    fn map_read_with_seeds(
+        &self,
+        read: &str,
+        seed_start_indices: &[usize],
+        options: &MapReadOptions,
+    ) -> Vec<MapReadResult> {
+        let seed_length = options.seed_length;
+
+        /*
+         * Reads from an unstranded library are equally likely to come from either strand of the
+         * genome. A read from the reverse strand appears in the (forwards) genome as its reverse
+         * complement, so we map both the read and its reverse complement.
+         */
+        let reverse_complement_read = reverse_complement(read);
+
+        for (seed_attempt, &seed_start_index) in seed_start_indices
+            .iter()
+            .take(options.max_seeds)
+            .enumerate()
+        {
+            let forwards_strand_matches = self.map_seed(read, seed_start_index, options);
+
+            /*
+             * Use the same bases of the read as the seed on the reverse strand. The start of the
+             * read is at the end of its reverse complement.
+             */
+            let reverse_strand_matches = self.map_seed(
+                &reverse_complement_read,
+                read.len() - seed_start_index - seed_length,
+                options,
+            );
+
+            // Every occurrence of the seed on either strand
+            let mut seed_matches: Vec<(Strand, SeedMatch)> = forwards_strand_matches
+                .into_iter()
+                .map(|seed_match| (Strand::Forward, seed_match))
+                .chain(
+                    reverse_strand_matches
+                        .into_iter()
+                        .map(|seed_match| (Strand::Reverse, seed_match)),
+                )
+                .collect();
+
+            // Try next seed if current seed not found on either strand
+            if seed_matches.is_empty() {
+                continue;
+            }
+
+            // Rank the hits, giving the same order every run whatever order they were found in
+            seed_matches.sort_by_key(|(strand, seed_match)| {
+                (
+                    std::cmp::Reverse(seed_match.score),
+                    seed_match.genome_position,
+                    *strand,
+                )
+            });
+
+            let best_score = seed_matches[0].1.score;
+            let tied_count = seed_matches
+                .iter()
+                .take_while(|(_, seed_match)| seed_match.score == best_score)
+                .count();
+
+            if let TieBreak::Random { seed } = options.tie_break {
+                let chosen_index = (read_hash(read, seed) % tied_count as u64) as usize;
+                seed_matches[..tied_count].rotate_left(chosen_index);
+            }
+
+            let mut hit_count = seed_matches.len();
+            let mut secondary_hit_count = options.max_hits.saturating_sub(1);
+            let mut second_best_score = seed_matches.get(1).map(|(_, seed_match)| seed_match.score);
+
+            let mut seed_matches = seed_matches.into_iter();
+            let (strand, mut seed_match) = seed_matches.next().unwrap();
+
+            /*
+             * Reads spanning an exon junction only match up to the end of the exon. Try chaining
+             * seed hits across introns if part of the read was left unmatched.
+             */
+            if let Some(splice_options) = &options.splicing {
+                if seed_match.read_end - seed_match.read_start < read.len() {
+                    let oriented_read = match strand {
+                        Strand::Forward => read,
+                        Strand::Reverse => &reverse_complement_read,
+                    };
+
+                    if let Some((spliced_match, spliced_second_best_score, spliced_hit_count)) =
+                        self.splice_read(oriented_read, splice_options, options)
+                    {
+                        /*
+                         * The spliced match competes with other chains, not the seed's other
+                         * hits, so those aren't reported as secondary hits either
+                         */
+                        if spliced_match.score > seed_match.score {
+                            seed_match = spliced_match;
+                            second_best_score = spliced_second_best_score;
+                            hit_count = spliced_hit_count;
+                            secondary_hit_count = 0;
+                        }
+                    }
+                }
+            }
+
+            let mapq = mapping_quality(
+                seed_match.score,
+                second_best_score,
+                hit_count,
+                read.len(),
+                &options.scoring,
+            );
+
+            // Reads which can't be placed confidently are unmapped, unless a later seed places them
+            if mapq < options.min_mapq {
+                continue;
+            }
+
+            let multi_mapped = second_best_score == Some(seed_match.score);
+
+            let mut primary_hit = seed_match.into_map_read_result(
+                &self.contigs,
+                seed_attempt,
+                strand,
+                read.len(),
+                mapq,
+            );
+            primary_hit.multi_mapped = multi_mapped;
+
+            // The other hits are alternatives to the primary hit, so have no confidence of their own
+            let secondary_hits =
+                seed_matches
+                    .take(secondary_hit_count)
+                    .map(|(strand, seed_match)| {
+                        let mut secondary_hit = seed_match.into_map_read_result(
+                            &self.contigs,
+                            seed_attempt,
+                            strand,
+                            read.len(),
+                            0,
+                        );
+                        secondary_hit.secondary = true;
+                        secondary_hit.multi_mapped = multi_mapped;
+                        return secondary_hit;
+                    });
+
+            return std::iter::once(primary_hit).chain(secondary_hits).collect();
+        }
+
+        // None of the seeds could be found
+        return Vec::new();
+    }
+
+    /*
+     * Finds a match in the forwards genome for each occurrence of the seed
+     * read[seed_start_index..seed_start_index + seed_length], best scoring first
+     */
+    fn map_seed(
+        &self,
+        read: &str,
+        seed_start_index: usize,
+        options: &MapReadOptions,
+    ) -> Vec<SeedMatch> {
+        let mut seed_matches: Vec<SeedMatch> = match options.extension_mode {
+            ExtensionMode::Naive => self
+                .extend_seed(read, seed_start_index, options)
+                .into_iter()
+                .map(|ungapped_match| ungapped_match.into_seed_match(read.len()))
+                .collect(),
+            ExtensionMode::BandedAlignment { band_width } => {
+                let mut seed_matches = self.align_seed(read, seed_start_index, band_width, options);
+
+                // Nearby occurrences of the seed (eg in a tandem repeat) can align identically
+                seed_matches.sort_by_key(|seed_match| seed_match.genome_position);
+                seed_matches
+                    .dedup_by(|a, b| a.genome_position == b.genome_position && a.cigar == b.cigar);
+                seed_matches
+            }
+        };
+
+        seed_matches.sort_by_key(|seed_match| std::cmp::Reverse(seed_match.score));
+        return seed_matches;
+    }
+
+    /*
+     * Maps a read which may span several exons by chaining together seed hits separated by
+     * introns. Every seed in the read is extended, and the chain of extended seeds which covers
+     * the most of the read is stitched together at splice junctions.
+     *
+     * Also returns the score of the best competing placement of any part of the read (another
+     * chain, or another occurrence of one of the chain's seeds), and the most occurrences of any
+     * of the chain's seeds, for estimating the mapping quality.
+     */
+    fn splice_read(
+        &self,
+        read: &str,
+        splice_options: &SpliceOptions,
+        options: &MapReadOptions,
+    ) -> Option<(SeedMatch, Option<isize>, usize)> {
+        let seed_length = options.seed_length;
+
+        // Also seed from the end of the read so that short final exons can be found
+        let mut seed_start_indices: Vec<_> = (0..read.len() / seed_length)
+            .map(|seed_index| seed_index * seed_length)
+            .collect();
+        if !read.len().is_multiple_of(seed_length) {
+            seed_start_indices.push(read.len() - seed_length);
+        }
+
+        // The best match of each seed, with how many times the seed occurs and the score of its
+        // next best match
+        let mut candidates: Vec<(UngappedMatch, (usize, Option<isize>))> = seed_start_indices
+            .into_iter()
+            .filter_map(|seed_start_index| {
+                let mut occurrences = self.extend_seed(read, seed_start_index, options);
+                // The leftmost of equally good matches, whatever order they were found in
+                occurrences
+                    .sort_by_key(|exon| (std::cmp::Reverse(exon.score()), exon.genome_position));
+
+                let hit_count = occurrences.len();
+                let second_best_score = occurrences.get(1).map(|exon| exon.score());
+                return occurrences
+                    .into_iter()
+                    .next()
+                    .map(|exon| (exon, (hit_count, second_best_score)));
+            })
+            .collect();
+
+        // Seeds from the same exon extend to the same match
+        candidates.sort_by_key(|(exon, _)| (exon.read_start, exon.genome_position));
+        candidates.dedup_by_key(|(exon, _)| (exon.read_start, exon.genome_position));
+        let (exons, seed_competitors): (Vec<UngappedMatch>, Vec<_>) =
+            candidates.into_iter().unzip();
+
+        // Introns can't span contigs
+        let exon_contigs: Vec<_> = exons
+            .iter()
+            .map(|exon| locate_in_contigs(&self.contigs, exon.genome_position).0)
+            .collect();
+
+        /*
+         * Find the highest scoring chain of exons. An exon can follow another if it continues
+         * further along the read, and is separated from it in the genome by an intron.
+         *
+         * Exons usually overlap in the read, since extension continues into the intron until it
+         * happens to mismatch. The overlap is only counted once when scoring the chain. We don't
+         * know the splice motif until the junction is placed, so assume every junction is
+         * non-canonical to avoid chaining spurious seed hits.
+         */
+        let mut chain_scores: Vec<isize> = exons.iter().map(|exon| exon.score()).collect();
+        let mut previous_exons: Vec<Option<usize>> = vec![None; exons.len()];
+
+        for next in 0..exons.len() {
+            for previous in 0..next {
+                let intron_length = exons[next].diagonal() - exons[previous].diagonal();
+
+                let can_follow = exon_contigs[next] == exon_contigs[previous]
+                    && exons[next].read_start > exons[previous].read_start
+                    && exons[next].read_start <= exons[previous].read_end()
+                    && exons[next].read_end() > exons[previous].read_end()
+                    && intron_length >= splice_options.min_intron_length() as isize
+                    && intron_length <= splice_options.max_intron_length() as isize;
+
+                if !can_follow {
+                    continue;
+                }
+
+                let overlap = exons[previous].read_end() - exons[next].read_start;
+                let chain_score = chain_scores[previous] + exons[next].score()
+                    - overlap as isize * MATCH_SCORE
+                    - splice_options.non_canonical_penalty();
+
+                if chain_score > chain_scores[next] {
+                    chain_scores[next] = chain_score;
+                    previous_exons[next] = Some(previous);
+                }
+            }
+        }
+
+        let last_exon = (0..exons.len()).max_by_key(|&exon| chain_scores[exon])?;
+
+        let mut chain = vec![last_exon];
+        while let Some(previous) = previous_exons[*chain.last().unwrap()] {
+            chain.push(previous);
+        }
+        chain.reverse();
+
+        // A single exon is not spliced
+        if chain.len() < 2 {
+            return None;
+        }
+
+        // Read positions where each exon ends and the next begins
+        let mut split_indices = Vec::new();
+        let mut junctions = Vec::new();
+        for window in chain.windows(2) {
+            let (split_index, junction) = self.split_junction(&exons[window[0]], &exons[window[1]]);
+            split_indices.push(split_index);
+            junctions.push(junction);
+        }
+
+        let first_exon = &exons[chain[0]];
+        let last_exon = &exons[*chain.last().unwrap()];
+
+        let genome_position = first_exon.genome_position;
+        let mut cigar = Cigar::new();
+        let mut mismatch_positions = Vec::new();
+        let mut score = 0;
+
+        cigar.push(CigarOp::SoftClip, first_exon.read_start);
+
+        for (chain_index, &exon_index) in chain.iter().enumerate() {
+            let exon = &exons[exon_index];
+
+            // Each exon is trimmed to the junctions either side of it
+            let read_start = match chain_index {
+                0 => exon.read_start,
+                _ => split_indices[chain_index - 1],
+            };
+            let read_end = split_indices
+                .get(chain_index)
+                .copied()
+                .unwrap_or(exon.read_end());
+
+            let exon_genome_start = (exon.diagonal() + read_start as isize) as usize;
+            let exon_genome_end = (exon.diagonal() + read_end as isize) as usize;
+
+            if chain_index > 0 {
+                let junction = &junctions[chain_index - 1];
+                cigar.push(CigarOp::Skip, junction.end - junction.start);
+
+                if !junction.motif.is_canonical() {
+                    score -= splice_options.non_canonical_penalty();
+                }
+            }
+            cigar.push(CigarOp::Match, read_end - read_start);
+
+            let exon_mismatches: Vec<_> = exon
+                .mismatch_positions
+                .iter()
+                .map(|position| exon.genome_position + position)
+                .filter(|position| (exon_genome_start..exon_genome_end).contains(position))
+                .collect();
+
+            let exon_length = (read_end - read_start) as isize;
+            let mismatch_count = exon_mismatches.len() as isize;
+            score +=
+                (exon_length - mismatch_count) * MATCH_SCORE - mismatch_count * MISMATCH_PENALTY;
+
+            mismatch_positions.extend(
+                exon_mismatches
+                    .into_iter()
+                    .map(|position| position - genome_position),
+            );
+        }
+
+        cigar.push(CigarOp::SoftClip, read.len() - last_exon.read_end());
+
+        /*
+         * Other chains place parts of the read elsewhere, unless they end on the same diagonal as
+         * an exon of this chain, in which case they're the same placement
+         */
+        let chain_diagonals: Vec<_> = chain.iter().map(|&exon| exons[exon].diagonal()).collect();
+        let other_chain_scores = (0..exons.len())
+            .filter(|exon| !chain_diagonals.contains(&exons[*exon].diagonal()))
+            .map(|exon| chain_scores[exon]);
+        let other_occurrence_scores = chain.iter().filter_map(|&exon| seed_competitors[exon].1);
+        let second_best_score = other_chain_scores.chain(other_occurrence_scores).max();
+
+        let hit_count = chain
+            .iter()
+            .map(|&exon| seed_competitors[exon].0)
+            .max()
+            .unwrap();
+
+        let spliced_match = SeedMatch {
+            genome_position,
+            match_length: cigar.genome_length(),
+            read_start: first_exon.read_start,
+            read_end: last_exon.read_end(),
+            edit_distance: mismatch_positions.len(),
+            mismatch_positions,
+            score,
+            cigar,
+            junctions,
+        };
+        return Some((spliced_match, second_best_score, hit_count));
+    }
+
+    /*
+     * Chooses where in the read `previous` ends and `next` begins. Any split in the overlap between
+     * the two exons is consistent with the read, so prefer one where the intron has a canonical
+     * splice motif. Otherwise, split at the earliest point.
+     */
+    fn split_junction(&self, previous: &UngappedMatch, next: &UngappedMatch) -> (usize, Junction) {
+        // Both exons must keep at least one base
+        let first_split = max(next.read_start, previous.read_start + 1);
+        let last_split = min(previous.read_end(), next.read_end() - 1);
+
+        let intron_start =
+            |split_index: usize| (previous.diagonal() + split_index as isize) as usize;
+        let intron_end = |split_index: usize| (next.diagonal() + split_index as isize) as usize;
+
+        /*
+         * The first and last two bases of every candidate intron. Introns are at least
+         * `MIN_INTRON_LENGTH` long, so these lie within the intron, between the two exons.
+         */
+        let donors = self
+            .forwards_fm_index
+            .extract(intron_start(first_split), intron_start(last_split) + 2);
+        let acceptors = self
+            .forwards_fm_index
+            .extract(intron_end(first_split) - 2, intron_end(last_split));
+
+        let junctions: Vec<_> = (first_split..=last_split)
+            .map(|split_index| {
+                let offset = split_index - first_split;
+                let (contig_index, start) =
+                    locate_in_contigs(&self.contigs, intron_start(split_index));
+                let junction = Junction {
+                    contig_index,
+                    start,
+                    end: start + intron_end(split_index) - intron_start(split_index),
+                    motif: SpliceMotif::classify(
+                        &donors[offset..offset + 2],
+                        &acceptors[offset..offset + 2],
+                    ),
+                };
+                return (split_index, junction);
+            })
+            .collect();
+
+        return junctions
+            .iter()
+            .find(|(_, junction)| junction.motif.is_canonical())
+            .copied()
+            .unwrap_or(junctions[0]);
+    }
+
+    // Aligns the whole read to the genome around each occurence of the seed
+    fn align_seed(
+        &self,
+        read: &str,
+        seed_start_index: usize,
+        band_width: usize,
+        options: &MapReadOptions,
+    ) -> Vec<SeedMatch> {
+        let seed = &read[seed_start_index..seed_start_index + options.seed_length];
+
+        return self
+            .forwards_fm_index
+            .lookup(seed)
+            .filter_map(|lookup_result| {
+                let seed_genome_pos = self.forwards_fm_index.get_genome_position(lookup_result);
+
+                /*
+                 * Where the start of the read would be in the genome if it had no indels. Indels
+                 * shift the read by at most `band_width` bases, so only consider the genome within
+                 * `band_width` of the read. The alignment can't leave the contig the seed is in.
+                 */
+                let (contig_index, _) = locate_in_contigs(&self.contigs, seed_genome_pos);
+                let contig = &self.contigs[contig_index];
+
+                let read_genome_pos = seed_genome_pos as isize - seed_start_index as isize;
+                let window_start = max(
+                    contig.offset as isize,
+                    read_genome_pos - band_width as isize,
+                ) as usize;
+                let window_end = min(
+                    contig.offset + contig.length,
+                    (read_genome_pos + (read.len() + band_width) as isize) as usize,
+                );
+                let window = self.forwards_fm_index.extract(window_start, window_end);
+
+                let alignment = banded_local_alignment(
+                    read,
+                    &window,
+                    read_genome_pos - window_start as isize,
+                    band_width,
+                    &options.scoring,
+                )?;
+
+                return Some(SeedMatch {
+                    genome_position: window_start + alignment.reference_start,
+                    match_length: alignment.cigar.genome_length(),
+                    read_start: alignment.read_start,
+                    read_end: alignment.read_end,
+                    mismatch_positions: alignment.mismatch_positions,
+                    score: alignment.score,
+                    cigar: alignment.cigar,
+                    edit_distance: alignment.edit_distance,
+                    junctions: Vec::new(),
+                });
+            })
+            .collect();
+    }
+
+    /*
+     * Aligns the read (or its reverse complement, for `Strand::Reverse`) anywhere within
+     * [window_start, window_end) of a contig, without needing a seed. Used to rescue the mate of a
+     * paired read, so only accepts alignments scoring at least half the read's maximum score.
+     */
+    pub(crate) fn align_in_window(
+        &self,
+        read: &str,
+        strand: Strand,
+        contig_index: usize,
+        window_start: usize,
+        window_end: usize,
+        scoring: &AlignmentScoring,
+    ) -> Option<MapReadResult> {
+        let oriented_read = match strand {
+            Strand::Forward => read.to_string(),
+            Strand::Reverse => reverse_complement(read),
+        };
+
+        let contig_offset = self.contigs[contig_index].offset;
+        let window = self
+            .forwards_fm_index
+            .extract(contig_offset + window_start, contig_offset + window_end);
+
+        // A band as wide as the window allows the read to align anywhere within it
+        let alignment = banded_local_alignment(&oriented_read, &window, 0, window.len(), scoring)?;
+        if alignment.score * 2 < read.len() as isize * scoring.match_score {
+            return None;
+        }
+
+        let mapq = mapping_quality(alignment.score, None, 1, read.len(), scoring);
+        let seed_match = SeedMatch {
+            genome_position: contig_offset + window_start + alignment.reference_start,
+            match_length: alignment.cigar.genome_length(),
+            read_start: alignment.read_start,
+            read_end: alignment.read_end,
+            mismatch_positions: alignment.mismatch_positions,
+            score: alignment.score,
+            cigar: alignment.cigar,
+            edit_distance: alignment.edit_distance,
+            junctions: Vec::new(),
+        };
+
+        return Some(seed_match.into_map_read_result(&self.contigs, 0, strand, read.len(), mapq));
+    }
+
+    // Extends each occurence of the seed in both directions by walking the FM indexes
+    fn extend_seed(
+        &self,
+        read: &str,
+        seed_start_index: usize,
+        options: &MapReadOptions,
+    ) -> Vec<UngappedMatch> {
+        let seed_length = options.seed_length;
+        let reversed_read: String = read.chars().rev().collect();
+
+        let seed_end_index = seed_start_index + seed_length;
+
+        // Corresponding bounds for the same seed in the reverse direction
+        let reverse_seed_start_index = read.len() - seed_end_index;
+        let reverse_seed_end_index = read.len() - seed_start_index;
+        let reverse_seed = &reversed_read[reverse_seed_start_index..reverse_seed_end_index];
+
+        /*
+         * We check for the seed in the reverse genome as often if there is a match for the
+         * first seed (which happens often), then we can extend to the rest of the read without
+         * consulting the forwards_fm_index
+         */
+        let mut reverse_lookup_results = self.reverse_fm_index.lookup(reverse_seed).peekable();
+
+        // Seed not found in genome
+        if reverse_lookup_results.peek().is_none() {
+            return Vec::new();
+        }
+
+        // Get the read extension from where the seed ends
+        let reverse_extension = &read[seed_end_index..];
+
+        // Maps the position of the seed in the forwards genome to the match containing it
+        let mut seed_matches: HashMap<_, _> = reverse_lookup_results
+            .map(|lookup_result| {
+                let reverse_genome_pos = self.reverse_fm_index.get_genome_position(lookup_result);
+                let extension = self.reverse_fm_index.count_extension_matches(
+                    lookup_result,
+                    reverse_extension,
+                    options.max_mismatches,
+                    options.x_drop,
+                );
+
+                /*
+                 * Converts to a genome_pos in the forwards genome.
+                 *
+                 * `self.genome_length - reverse_genome_pos` is classic index reversal
+                 *
+                 * `-seed_length` since the beginning of the seed in the reverse genome
+                 * is the end of the seed in the forwards genome
+                 *
+                 * `-1` since the sentinel is at the end of the genomes in both cases
+                 */
+                let genome_pos = self.genome_length - reverse_genome_pos - seed_length - 1;
+
+                // Mismatches in the extension are offset by the seed preceding it
+                let seed_match = UngappedMatch {
+                    genome_position: genome_pos,
+                    read_start: seed_start_index,
+                    match_length: seed_length + extension.match_length,
+                    mismatch_positions: extension
+                        .mismatch_positions
+                        .into_iter()
+                        .map(|position| seed_length + position)
+                        .collect(),
+                };
+                return (genome_pos, seed_match);
+            })
+            .collect();
+
+        let forwards_extension = &reversed_read[read.len() - seed_start_index..read.len()];
+
+        /*
+         * Only consider forwards_fm_index if we have something to gain.
+         * Note that without mismatches the forwards_extension_length can be at most the
+         * seed_length, as if it was longer then the previous seed would have matched in the
+         * reverse_fm_index lookup.
+         */
+        if !forwards_extension.is_empty() {
+            let forwards_seed = &read[seed_start_index..seed_end_index];
+
+            let forwards_lookup_results = self.forwards_fm_index.lookup(forwards_seed);
+
+            for lookup_result in forwards_lookup_results {
+                let forwards_genome_pos = self.forwards_fm_index.get_genome_position(lookup_result);
+
+                /*
+                 * Extract the match from the same seed found by the reverse lookup
+                 *
+                 *       <--forwards_extension_length--|-----reverse_match_length------>
+                 *       <-----forwards_extension------|--seed--|--reverse_extension--->
+                 * ------|-----------------------------|--------|----------------------|------
+                 *       ^                             ^
+                 *    genome_pos              forwards_genome_pos
+                 *
+                 * Should always be something here since the reverse lookup should populate it
+                 * with at least the length of the seed. Hence, we do `unwrap` to panic if
+                 * something has gone wrong
+                 */
+                let seed_match = seed_matches.get_mut(&forwards_genome_pos).unwrap();
+
+                // Mismatches are shared between both directions of extension
+                let extension = self.forwards_fm_index.count_extension_matches(
+                    lookup_result,
+                    forwards_extension,
+                    options.max_mismatches - seed_match.mismatch_positions.len(),
+                    options.x_drop,
+                );
+                let forwards_extension_length = extension.match_length;
+
+                /*
+                 * Re-offset all the mismatches from the new start of the match. The forwards
+                 * extension is read backwards from the seed so its mismatches are reversed.
+                 */
+                let mut mismatch_positions: Vec<_> = extension
+                    .mismatch_positions
+                    .into_iter()
+                    .rev()
+                    .map(|position| forwards_extension_length - position - 1)
+                    .collect();
+                mismatch_positions.extend(
+                    seed_match
+                        .mismatch_positions
+                        .iter()
+                        .map(|position| position + forwards_extension_length),
+                );
+
+                seed_match.genome_position = forwards_genome_pos - forwards_extension_length;
+                seed_match.read_start -= forwards_extension_length;
+                seed_match.match_length += forwards_extension_length;
+                seed_match.mismatch_positions = mismatch_positions;
+            }
+        }
+
+        return seed_matches.into_values().collect();
+    }
+}
+
+// FNV-1a hash of the read, mixed with `seed`, which is the same on every platform and run
+fn read_hash(read: &str, seed: u64) -> u64 {
+    let mut hash = 0xcbf29ce484222325 ^ seed;
+    for &byte in read.as_bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+/*
+ * Start of each non-overlapping seed in the read, from the beginning of the read onwards. Seeds
+ * which would overlap an ambiguous base (eg N) can never be found in the index, so are skipped by
+ * starting the next seed just after the ambiguous base.
+ */
+fn seed_start_indices(read: &str, seed_length: usize) -> Vec<usize> {
+    let read = read.as_bytes();
+    let mut seed_start_indices = Vec::new();
+
+    let mut seed_start_index = 0;
+    while seed_start_index + seed_length <= read.len() {
+        let seed = &read[seed_start_index..seed_start_index + seed_length];
 
-    pub fn from_file(filename: &str) -> Self {
-        let mut file = File::open(filename).unwrap();
-        decode_from_std_read(&mut file, standard()).unwrap()
+        match seed
+            .iter()
+            .rposition(|nuc| !matches!(nuc, b'A' | b'C' | b'G' | b'T'))
+        {
+            Some(ambiguous_index) => seed_start_index += ambiguous_index + 1,
+            None => {
+                seed_start_indices.push(seed_start_index);
+                seed_start_index += seed_length;
+            }
+        }
     }
 
-    pub fn map_read(
-        &self,
-        read: &str,
-        seed_length: usize,
-        max_seeds: usize,
-    ) -> Option<MapReadResult> {
-        assert!(read.len() >= seed_length);
-        let reversed_read: String = read.chars().rev().collect();
+    return seed_start_indices;
+}
 
-        for seed_attempt in 0..min(read.len() / seed_length, max_seeds) {
-            /*
-             * Seed bounds in the forward direction
-             * We start by choosing seeds from the beginning of the reads since
-             * reads are typically more accurate at the beginning
-             */
-            let seed_start_index = seed_attempt * seed_length;
-            let seed_end_index = seed_start_index + seed_length;
+/*
+ * Start of non-overlapping seeds in the read, best first. The quality of a seed is the lowest
+ * quality of any of its bases, since a single sequencing error stops the seed being found. Seeds
+ * below `min_seed_quality` or containing an ambiguous base are never chosen, and ties go to the
+ * seed nearer the start of the read.
+ */
+fn quality_seed_start_indices(
+    read: &str,
+    qualities: &str,
+    seed_length: usize,
+    min_seed_quality: u8,
+) -> Vec<usize> {
+    let read = read.as_bytes();
+    // Phred+33 encoding
+    let qualities: Vec<u8> = qualities
+        .bytes()
+        .map(|qual| qual.saturating_sub(33))
+        .collect();
 
-            // Corresponding bounds for the same seed in the reverse direction
-            let reverse_seed_start_index = read.len() - seed_end_index;
-            let reverse_seed_end_index = read.len() - seed_start_index;
-            let reverse_seed = &reversed_read[reverse_seed_start_index..reverse_seed_end_index];
+    let mut candidates: Vec<(u8, usize)> = (0..=read.len() - seed_length)
+        .filter(|&start| {
+            read[start..start + seed_length]
+                .iter()
+                .all(|nuc| matches!(nuc, b'A' | b'C' | b'G' | b'T'))
+        })
+        .map(|start| {
+            let seed_quality = *qualities[start..start + seed_length].iter().min().unwrap();
+            (seed_quality, start)
+        })
+        .filter(|&(seed_quality, _)| seed_quality >= min_seed_quality)
+        .collect();
 
-            /*
-             * We check for the seed in the reverse genome as often if there is a match for the
-             * first seed (which happens often), then we can extend to the rest of the read without
-             * consulting the forwards_fm_index
-             */
-            let mut reverse_lookup_results = self.reverse_fm_index.lookup(&reverse_seed).peekable();
+    // Highest quality first, then earliest in the read
+    candidates.sort_by_key(|&(seed_quality, start)| (std::cmp::Reverse(seed_quality), start));
 
-            // Try next seed if current seed not found in genome
-            if reverse_lookup_results.peek().is_none() {
-                continue;
-            }
+    let mut seed_start_indices: Vec<usize> = Vec::new();
+    for (_, start) in candidates {
+        let overlaps_chosen_seed = seed_start_indices
+            .iter()
+            .any(|&chosen_start| start.abs_diff(chosen_start) < seed_length);
 
-            // Get the read extension from where the seed ends
-            let reverse_extension = &read[seed_end_index..];
-
-            let mut match_length_map: HashMap<_, _> = reverse_lookup_results
-                .map(|lookup_result| {
-                    let reverse_genome_pos =
-                        self.reverse_fm_index.get_genome_position(lookup_result);
-                    let extension_length = self
-                        .reverse_fm_index
-                        .count_extension_matches(lookup_result, reverse_extension);
-
-                    /*
-                     * Converts to a genome_pos in the forwards genome.
-                     *
-                     * `self.genome_length - reverse_genome_pos` is classic index reversal
-                     *
-                     * `-seed_length` since the beginning of the seed in the reverse genome
-                     * is the end of the seed in the forwards genome
-                     *
-                     * `-1` since the sentinel is at the end of the genomes in both cases
-                     */
-                    let genome_pos = self.genome_length - reverse_genome_pos - seed_length - 1;
-                    let match_length = seed_length + extension_length;
-                    return (genome_pos, match_length);
-                })
-                .collect();
+        if !overlaps_chosen_seed {
+            seed_start_indices.push(start);
+        }
+    }
 
-            let forwards_extension = &reversed_read[read.len() - seed_start_index..read.len()];
+    return seed_start_indices;
+}
 
-            /*
-             * Only consider forwards_fm_index if we have something to gain.
-             * Note that the forwards_extension_length can be at most the seed_length,
-             * as if it was longer then the previous seed would have matched in the
-             * reverse_fm_index lookup.
-             */
-            if forwards_extension.len() > 0 {
-                let forwards_seed = &read[seed_start_index..seed_end_index];
-
-                let forwards_lookup_results = self.forwards_fm_index.lookup(forwards_seed);
-
-                for lookup_result in forwards_lookup_results {
-                    let forwards_genome_pos =
-                        self.forwards_fm_index.get_genome_position(lookup_result);
-                    let forwards_extension_length = self
-                        .forwards_fm_index
-                        .count_extension_matches(lookup_result, forwards_extension);
-
-                    // The position where the left-extension in the forwards genome begins
-                    let genome_pos = forwards_genome_pos - forwards_extension_length;
-
-                    /*
-                     * If we already have the length of a reverse match from the same seed extract it
-                     *
-                     *       <--forwards_extension_length--|-----reverse_match_length------>
-                     *       <-----forwards_extension------|--seed--|--reverse_extension--->
-                     * ------|-----------------------------|--------|----------------------|------
-                     *       ^                             ^
-                     *    genome_pos              forwards_genome_pos
-                     *
-                     * Should always be something here since the reverse lookup should populate it
-                     * with at least the length of the seed. Hence, we do `unwrap` to panic if
-                     * something has gone wrong
-                     */
-                    let reverse_match_length =
-                        match_length_map.remove(&forwards_genome_pos).unwrap();
-
-                    // TODO: Can there already be something at genome_pos?
-                    match_length_map
-                        .insert(genome_pos, forwards_extension_length + reverse_match_length);
+/*
+ * Replaces every ambiguous IUPAC code in a contig with `NON_NUCLEOTIDE`, recording each run of
+ * ambiguous codes in `ambiguous_spans`. Fails on anything which isn't an IUPAC code.
+ */
+fn mask_ambiguous_bases(
+    record: &FastaRecord,
+    contig_index: usize,
+    ambiguous_spans: &mut Vec<AmbiguousSpan>,
+) -> Result<String, IndexBuildError> {
+    return record
+        .sequence
+        .chars()
+        .enumerate()
+        .map(|(position, code)| {
+            let code = code.to_ascii_uppercase();
+
+            match code {
+                'A' | 'C' | 'G' | 'T' => return Ok(code),
+                // Uracil in RNA references pairs like thymine
+                'U' => return Ok('T'),
+                'R' | 'Y' | 'S' | 'W' | 'K' | 'M' | 'B' | 'D' | 'H' | 'V' | 'N' | '-' | '.' => {}
+                _ => {
+                    return Err(IndexBuildError::InvalidBase {
+                        contig_name: record.name.clone(),
+                        position,
+                        base: code,
+                    })
                 }
             }
 
-            // Return longest match
-            let longest_match = match_length_map
-                .into_iter()
-                .max_by_key(|(_, length_ref)| *length_ref)
-                .unwrap();
+            // Extend the previous span if this continues its run
+            match ambiguous_spans.last_mut() {
+                Some(span)
+                    if span.contig_index == contig_index
+                        && span.code == code
+                        && span.start + span.length == position =>
+                {
+                    span.length += 1
+                }
+                _ => ambiguous_spans.push(AmbiguousSpan {
+                    contig_index,
+                    start: position,
+                    length: 1,
+                    code,
+                }),
+            }
 
-            return Some(MapReadResult {
-                genome_position: longest_match.0,
-                match_length: longest_match.1,
-                seed_attempt: seed_attempt,
-            });
-        }
+            return Ok(NON_NUCLEOTIDE);
+        })
+        .collect();
+}
 
-        // None of the seeds could be found
-        return None;
-    }
+// Finds the contig containing a position in the concatenated genome, and the position within it
+fn locate_in_contigs(contigs: &[Contig], genome_position: usize) -> (usize, usize) {
+    let contig_index = contigs.partition_point(|contig| contig.offset <= genome_position) - 1;
+    return (contig_index, genome_position - contigs[contig_index].offset);
+}
+
+// Reverse complement of a read. Any character which is not a nucleotide is complemented to 'N'
+pub fn reverse_complement(read: &str) -> String {
+    return read
+        .chars()
+        .rev()
+        .map(|nucleotide| match nucleotide {
+            'A' => 'T',
+            'C' => 'G',
+            'G' => 'C',
+            'T' => 'A',
+            _ => 'N',
+        })
+        .collect();
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::splicing::JunctionTable;
+
+    fn test_index(genome: &str) -> ReadMappingIndex {
+        return ReadMappingIndex::new(&[FastaRecord::new("genome", genome)]).unwrap();
+    }
 
     #[test]
     fn map_read() {
-        let read_mapping_index =
-            ReadMappingIndex::new("ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT");
+        let read_mapping_index = test_index("ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT");
 
         // |------| is used to highlight the matches between the reads and the genome
 
@@ -190,9 +1483,21 @@ mod tests {
             //                           |--|
             read_mapping_index.map_read("ATAC", 4, 1),
             Some(MapReadResult {
-                genome_position: 0,
+                contig_index: 0,
+                position: 0,
                 match_length: 4,
-                seed_attempt: 0
+                read_start: 0,
+                read_end: 4,
+                seed_attempt: 0,
+                mapq: 0,
+                secondary: false,
+                multi_mapped: true,
+                strand: Strand::Forward,
+                mismatch_positions: vec![],
+                alignment_score: 4,
+                cigar: "4M".parse().unwrap(),
+                edit_distance: 0,
+                junctions: vec![]
             })
         );
 
@@ -200,9 +1505,21 @@ mod tests {
             //                           |----------------|
             read_mapping_index.map_read("ATACTTTATCAAATGTAA", 5, 2),
             Some(MapReadResult {
-                genome_position: 0,
+                contig_index: 0,
+                position: 0,
                 match_length: 18,
-                seed_attempt: 0
+                read_start: 0,
+                read_end: 18,
+                seed_attempt: 0,
+                mapq: 34,
+                secondary: false,
+                multi_mapped: false,
+                strand: Strand::Forward,
+                mismatch_positions: vec![],
+                alignment_score: 18,
+                cigar: "18M".parse().unwrap(),
+                edit_distance: 0,
+                junctions: vec![]
             })
         );
 
@@ -210,9 +1527,21 @@ mod tests {
             //                           |------------|
             read_mapping_index.map_read("ATCAAATGTAAAAG", 7, 2),
             Some(MapReadResult {
-                genome_position: 7,
+                contig_index: 0,
+                position: 7,
                 match_length: 14,
-                seed_attempt: 0
+                read_start: 0,
+                read_end: 14,
+                seed_attempt: 0,
+                mapq: 60,
+                secondary: false,
+                multi_mapped: false,
+                strand: Strand::Forward,
+                mismatch_positions: vec![],
+                alignment_score: 14,
+                cigar: "14M".parse().unwrap(),
+                edit_distance: 0,
+                junctions: vec![]
             })
         );
 
@@ -224,9 +1553,21 @@ mod tests {
             //                            |---------------|
             read_mapping_index.map_read("TTACTTTATCAAATGTAA", 5, 2),
             Some(MapReadResult {
-                genome_position: 1,
+                contig_index: 0,
+                position: 1,
                 match_length: 17,
-                seed_attempt: 1
+                read_start: 1,
+                read_end: 18,
+                seed_attempt: 1,
+                mapq: 57,
+                secondary: false,
+                multi_mapped: false,
+                strand: Strand::Forward,
+                mismatch_positions: vec![],
+                alignment_score: 17,
+                cigar: "1S17M".parse().unwrap(),
+                edit_distance: 0,
+                junctions: vec![]
             })
         );
 
@@ -234,9 +1575,21 @@ mod tests {
             //                             |--------------|
             read_mapping_index.map_read("AAACTTTATCAAATGTAA", 5, 2),
             Some(MapReadResult {
-                genome_position: 2,
+                contig_index: 0,
+                position: 2,
                 match_length: 16,
-                seed_attempt: 1
+                read_start: 2,
+                read_end: 18,
+                seed_attempt: 1,
+                mapq: 53,
+                secondary: false,
+                multi_mapped: false,
+                strand: Strand::Forward,
+                mismatch_positions: vec![],
+                alignment_score: 16,
+                cigar: "2S16M".parse().unwrap(),
+                edit_distance: 0,
+                junctions: vec![]
             })
         );
 
@@ -244,9 +1597,21 @@ mod tests {
             //                                     |--------------|
             read_mapping_index.map_read("GTATCTTCTACGTTTACGTCTAATTT", 7, 3),
             Some(MapReadResult {
-                genome_position: 30,
+                contig_index: 0,
+                position: 30,
                 match_length: 16,
-                seed_attempt: 2
+                read_start: 10,
+                read_end: 26,
+                seed_attempt: 2,
+                mapq: 37,
+                secondary: false,
+                multi_mapped: false,
+                strand: Strand::Forward,
+                mismatch_positions: vec![],
+                alignment_score: 16,
+                cigar: "10S16M".parse().unwrap(),
+                edit_distance: 0,
+                junctions: vec![]
             })
         );
 
@@ -254,18 +1619,773 @@ mod tests {
             //                                     |-----------|
             read_mapping_index.map_read("GTATCTTCTACGTTTACGTCTAAATT", 7, 3),
             Some(MapReadResult {
-                genome_position: 30,
+                contig_index: 0,
+                position: 30,
                 match_length: 13,
-                seed_attempt: 2
+                read_start: 10,
+                read_end: 23,
+                seed_attempt: 2,
+                mapq: 30,
+                secondary: false,
+                multi_mapped: false,
+                strand: Strand::Forward,
+                mismatch_positions: vec![],
+                alignment_score: 13,
+                cigar: "10S13M3S".parse().unwrap(),
+                edit_distance: 0,
+                junctions: vec![]
+            })
+        );
+    }
+
+    #[test]
+    fn map_read_reverse_strand() {
+        let read_mapping_index = test_index("ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT");
+
+        assert_eq!(reverse_complement("ATCAAATGTAAAAG"), "CTTTTACATTTGAT");
+
+        assert_eq!(
+            // Reverse complement of ATCAAATGTAAAAG
+            read_mapping_index.map_read("CTTTTACATTTGAT", 7, 2),
+            Some(MapReadResult {
+                contig_index: 0,
+                position: 7,
+                match_length: 14,
+                read_start: 0,
+                read_end: 14,
+                seed_attempt: 0,
+                mapq: 60,
+                secondary: false,
+                multi_mapped: false,
+                strand: Strand::Reverse,
+                mismatch_positions: vec![],
+                alignment_score: 14,
+                cigar: "14M".parse().unwrap(),
+                edit_distance: 0,
+                junctions: vec![]
+            })
+        );
+
+        assert_eq!(
+            // Reverse complement of GTATCTTCTACGTTTACGTCTAATTT
+            read_mapping_index.map_read("AAATTAGACGTAAACGTAGAAGATAC", 7, 3),
+            Some(MapReadResult {
+                contig_index: 0,
+                position: 30,
+                match_length: 16,
+                read_start: 0,
+                read_end: 16,
+                seed_attempt: 0,
+                mapq: 37,
+                secondary: false,
+                multi_mapped: false,
+                strand: Strand::Reverse,
+                mismatch_positions: vec![],
+                alignment_score: 16,
+                cigar: "10S16M".parse().unwrap(),
+                edit_distance: 0,
+                junctions: vec![]
+            })
+        );
+    }
+
+    #[test]
+    fn map_read_with_mismatches() {
+        let read_mapping_index = test_index("ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT");
+
+        //              Mismatch against the genome here ⌄
+        let read = "ATCAAATGTAAAAGTATCTCATTCGTTTACGTCTAATTTT";
+
+        let mut options = MapReadOptions::new(10, 1);
+        assert_eq!(
+            read_mapping_index.map_read_with_options(read, &options),
+            Some(MapReadResult {
+                contig_index: 0,
+                position: 7,
+                match_length: 20,
+                read_start: 0,
+                read_end: 20,
+                seed_attempt: 0,
+                mapq: 30,
+                secondary: false,
+                multi_mapped: false,
+                strand: Strand::Forward,
+                mismatch_positions: vec![],
+                alignment_score: 20,
+                cigar: "20M20S".parse().unwrap(),
+                edit_distance: 0,
+                junctions: vec![]
+            })
+        );
+
+        options.max_mismatches = 1;
+        assert_eq!(
+            read_mapping_index.map_read_with_options(read, &options),
+            Some(MapReadResult {
+                contig_index: 0,
+                position: 7,
+                match_length: 40,
+                read_start: 0,
+                read_end: 40,
+                seed_attempt: 0,
+                mapq: 53,
+                secondary: false,
+                multi_mapped: false,
+                strand: Strand::Forward,
+                mismatch_positions: vec![20],
+                alignment_score: 35,
+                cigar: "40M".parse().unwrap(),
+                edit_distance: 1,
+                junctions: vec![]
+            })
+        );
+
+        assert_eq!(
+            read_mapping_index.map_read_with_options(&reverse_complement(read), &options),
+            Some(MapReadResult {
+                contig_index: 0,
+                position: 7,
+                match_length: 40,
+                read_start: 0,
+                read_end: 40,
+                seed_attempt: 0,
+                mapq: 53,
+                secondary: false,
+                multi_mapped: false,
+                strand: Strand::Reverse,
+                mismatch_positions: vec![20],
+                alignment_score: 35,
+                cigar: "40M".parse().unwrap(),
+                edit_distance: 1,
+                junctions: vec![]
+            })
+        );
+    }
+
+    #[test]
+    fn map_read_containing_n() {
+        let read_mapping_index = test_index("ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT");
+
+        // Seeds overlapping the N are skipped
+        assert_eq!(seed_start_indices("ACGNACGTACGTNAC", 4), vec![4, 8]);
+
+        //     The N is a mismatch ⌄
+        let read = "ATCAAATNTAAAAGTATCTCCTTCGTTTACG";
+
+        let mut options = MapReadOptions::new(10, 1);
+        options.max_mismatches = 1;
+        assert_eq!(
+            read_mapping_index.map_read_with_options(read, &options),
+            Some(MapReadResult {
+                contig_index: 0,
+                position: 7,
+                match_length: 31,
+                read_start: 0,
+                read_end: 31,
+                seed_attempt: 0,
+                mapq: 50,
+                secondary: false,
+                multi_mapped: false,
+                strand: Strand::Forward,
+                mismatch_positions: vec![7],
+                alignment_score: 26,
+                cigar: "31M".parse().unwrap(),
+                edit_distance: 1,
+                junctions: vec![]
+            })
+        );
+    }
+
+    #[test]
+    fn map_read_with_qualities() {
+        let read_mapping_index = test_index("ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT");
+
+        // The first seed holds a low quality base, so the best seed comes from later in the read
+        assert_eq!(
+            quality_seed_start_indices("ACGTACGTACGTA", "II#IIIIII5III", 4, 10),
+            vec![3, 7]
+        );
+        assert_eq!(
+            quality_seed_start_indices("ACGTACGTACGTA", "II#IIIIII5III", 4, 30),
+            vec![3]
+        );
+
+        // Sequencing error at the fourth base, which is marked as low quality
+        let read = "ATCGAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTT";
+        let qualities = "III#IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII";
+
+        let options = MapReadOptions::new(10, 1);
+        assert_eq!(
+            read_mapping_index.map_read_with_options(read, &options),
+            None
+        );
+
+        let result = read_mapping_index
+            .map_read_with_qualities(read, qualities, &options)
+            .unwrap();
+        assert_eq!(result.position, 11);
+        assert_eq!(result.cigar.to_string(), "4S36M");
+    }
+
+    #[test]
+    fn map_read_with_min_mapq() {
+        let read_mapping_index = test_index("ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT");
+
+        // Also present on the reverse strand as GTAT, so could have come from either
+        let mut options = MapReadOptions::new(4, 1);
+        assert_eq!(
+            read_mapping_index
+                .map_read_with_options("ATAC", &options)
+                .unwrap()
+                .mapq,
+            0
+        );
+
+        options.min_mapq = 1;
+        assert_eq!(
+            read_mapping_index.map_read_with_options("ATAC", &options),
+            None
+        );
+
+        // Extending the seed shows this read belongs at the start of the genome
+        let result = read_mapping_index.map_read_with_options("ATACTTTATCAAATGTAA", &options);
+        assert_eq!(result.unwrap().position, 0);
+    }
+
+    #[test]
+    fn map_read_with_min_mapq_tries_later_seeds() {
+        // TTTATTACAC is repeated, and the base after it differs between the read and both copies
+        let read_mapping_index = test_index(concat!(
+            "GGACTGGCA",
+            "TTTATTACAC",
+            "T",
+            "TCAGAAACAG",
+            "GG",
+            "TTTATTACAC",
+            "C",
+            "CCATGA"
+        ));
+
+        // The low quality base keeps seeds off the sequencing error, so the repeat is seeded first
+        let read = "TTTATTACACATCAGAAACAG";
+        let qualities = "IIIIIIIIII#IIIIIIIIII";
+
+        let mut options = MapReadOptions::new(10, 1);
+        options.min_mapq = 1;
+        assert_eq!(
+            read_mapping_index.map_read_with_qualities(read, qualities, &options),
+            None
+        );
+
+        // The second seed is unique, so places the read even though the first couldn't
+        options.max_seeds = 2;
+        let result = read_mapping_index
+            .map_read_with_qualities(read, qualities, &options)
+            .unwrap();
+        assert_eq!(result.position, 20);
+        assert_eq!(result.cigar.to_string(), "11S10M");
+        assert!(result.mapq >= options.min_mapq);
+    }
+
+    #[test]
+    fn map_read_hits() {
+        let read_mapping_index = test_index("GGACTGGCATTTTATTACACTCAGAAACAGTTTATTACACTCGGGT");
+
+        // Present twice in the genome
+        let read = "TTTATTACACTC";
+
+        let mut options = MapReadOptions::new(12, 1);
+        assert_eq!(
+            read_mapping_index.map_read_hits(read, None, &options).len(),
+            1
+        );
+
+        options.max_hits = usize::MAX;
+        let hits = read_mapping_index.map_read_hits(read, None, &options);
+        assert_eq!(hits.len(), 2);
+
+        let mut positions: Vec<_> = hits.iter().map(|hit| hit.position).collect();
+        positions.sort();
+        assert_eq!(positions, vec![10, 30]);
+
+        assert!(!hits[0].secondary);
+        assert!(hits[1].secondary);
+        assert_eq!(hits[0].alignment_score, hits[1].alignment_score);
+        assert_eq!(hits[0].mapq, 0);
+    }
+
+    #[test]
+    fn map_read_tie_break() {
+        let read_mapping_index = test_index("GGACTGGCATTTTATTACACTCAGAAACAGTTTATTACACTCGGGT");
+
+        // Present twice in the genome, so either position is as good as the other
+        let read = "TTTATTACACTC";
+
+        let mut options = MapReadOptions::new(12, 1);
+        let result = read_mapping_index
+            .map_read_with_options(read, &options)
+            .unwrap();
+        assert_eq!(result.position, 10);
+        assert!(result.multi_mapped);
+
+        // The same seed always picks the same hit, and some seed picks the other hit
+        let mut positions = Vec::new();
+        for seed in 0..16 {
+            options.tie_break = TieBreak::Random { seed };
+            let result = read_mapping_index
+                .map_read_with_options(read, &options)
+                .unwrap();
+            let repeated = read_mapping_index
+                .map_read_with_options(read, &options)
+                .unwrap();
+            assert_eq!(result.position, repeated.position);
+            assert!(result.multi_mapped);
+            positions.push(result.position);
+        }
+        positions.sort();
+        positions.dedup();
+        assert_eq!(positions, vec![10, 30]);
+
+        // A read with a single best hit is not multi-mapped
+        let result = read_mapping_index
+            .map_read("TTTATTACACTCAGA", 12, 1)
+            .unwrap();
+        assert_eq!(result.position, 10);
+        assert!(!result.multi_mapped);
+    }
+
+    #[test]
+    fn map_read_reports_read_coordinates() {
+        let read_mapping_index = test_index("ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT");
+
+        // The first seed fails since the first 5 bases are not in the genome
+        let read = "CCCCCAGACGTAAACGAAGG";
+
+        let result = read_mapping_index.map_read(read, 10, 2).unwrap();
+        assert_eq!(result.seed_attempt, 1);
+        assert_eq!(result.strand, Strand::Reverse);
+        assert_eq!((result.read_start, result.read_end), (5, 20));
+        // The CIGAR is for the reverse complement, so the clipped bases are at the end
+        assert_eq!(result.cigar.to_string(), "15M5S");
+    }
+
+    #[test]
+    fn map_read_with_banded_alignment() {
+        let read_mapping_index = test_index("ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT");
+
+        // Missing the C at position 30 of the genome
+        let read = "ATCAAATGTAAAAGTATCTCCTTGTTTACGTCTAATTTT";
+
+        let mut options = MapReadOptions::new(10, 1);
+        let naive_result = read_mapping_index
+            .map_read_with_options(read, &options)
+            .unwrap();
+        assert_eq!(naive_result.match_length, 23);
+        assert_eq!(naive_result.cigar.to_string(), "23M16S");
+
+        options.extension_mode = ExtensionMode::BandedAlignment { band_width: 5 };
+        for (read, strand) in [
+            (read.to_string(), Strand::Forward),
+            (reverse_complement(read), Strand::Reverse),
+        ] {
+            let result = read_mapping_index
+                .map_read_with_options(&read, &options)
+                .unwrap();
+            assert_eq!(result.position, 7);
+            assert_eq!(result.match_length, 40);
+            assert_eq!(result.strand, strand);
+            assert_eq!(result.alignment_score, 39 - 8);
+            assert_eq!(result.cigar.to_string(), "23M1D16M");
+            assert_eq!(result.edit_distance, 1);
+        }
+    }
+
+    #[test]
+    fn map_read_spliced() {
+        // Two exons separated by a GT-AG intron between positions 40 and 80
+        let read_mapping_index = test_index(concat!(
+            "GGACTGGCAT",
+            "TTTTATTACACTCAGAAACAGAACTCGGGT",
+            "GTAATTTTGACAGGTCACGCAGAGGCGCGCCCTCCTGAAG",
+            "AGTGCGTGGACACTCGCTATGAATCTCTGA",
+            "TTTACCCACT"
+        ));
+
+        let read = "TTTTATTACACTCAGAAACAGAACTCGGGTAGTGCGTGGACACTCGCTATGAATCTCTGA";
+
+        let mut options = MapReadOptions::new(10, 6);
+        let unspliced_result = read_mapping_index
+            .map_read_with_options(read, &options)
+            .unwrap();
+        assert_eq!(unspliced_result.cigar.to_string(), "30M30S");
+
+        options.splicing = Some(SpliceOptions::default());
+        let junction = Junction {
+            contig_index: 0,
+            start: 40,
+            end: 80,
+            motif: SpliceMotif::GtAg,
+        };
+
+        let mut junction_table = JunctionTable::new();
+        for (read, strand) in [
+            (read.to_string(), Strand::Forward),
+            (reverse_complement(read), Strand::Reverse),
+        ] {
+            let result = read_mapping_index
+                .map_read_with_options(&read, &options)
+                .unwrap();
+            assert_eq!(result.position, 10);
+            assert_eq!(result.strand, strand);
+            assert_eq!(result.cigar.to_string(), "30M40N30M");
+            assert_eq!(result.alignment_score, 60);
+            assert_eq!(result.junctions, vec![junction]);
+
+            junction_table.add_read(&result);
+        }
+
+        let mut bed = Vec::new();
+        junction_table
+            .write_bed(&mut bed, read_mapping_index.contigs())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(bed).unwrap(),
+            "genome\t40\t80\tJUNC00000001\t2\t+\n"
+        );
+
+        // The first exon aligns with the rest of the read soft clipped, so is spliced the same way
+        options.extension_mode = ExtensionMode::BandedAlignment { band_width: 4 };
+        let result = read_mapping_index
+            .map_read_with_options(read, &options)
+            .unwrap();
+        assert_eq!(result.cigar.to_string(), "30M40N30M");
+        assert_eq!(result.junctions, vec![junction]);
+    }
+
+    #[test]
+    fn map_read_spliced_mapq() {
+        // The second exon is repeated after the end of the gene
+        let read_mapping_index = test_index(concat!(
+            "GGACTGGCAT",
+            "TTTTATTACACTCAGAAACAGAACTCGGGT",
+            "GTAATTTTGACAGGTCACGCAGAGGCGCGCCCTCCTGAAG",
+            "AGTGCGTGGACACTCGCTATGAATCTCTGA",
+            "TTTACCCACC",
+            "AGTGCGTGGACACTCGCTATGAATCTCTGA",
+            "CCATGA"
+        ));
+
+        let read = "TTTTATTACACTCAGAAACAGAACTCGGGTAGTGCGTGGACACTCGCTATGAATCTCTGA";
+
+        let mut options = MapReadOptions::new(10, 6);
+        let unspliced_result = read_mapping_index
+            .map_read_with_options(read, &options)
+            .unwrap();
+        assert_eq!(unspliced_result.cigar.to_string(), "30M30S");
+        assert_eq!(unspliced_result.mapq, 30);
+
+        /*
+         * The whole read is covered, but the second exon could be placed elsewhere, which the
+         * unique seed in the first exon doesn't show
+         */
+        options.splicing = Some(SpliceOptions::default());
+        let result = read_mapping_index
+            .map_read_with_options(read, &options)
+            .unwrap();
+        assert_eq!(result.cigar.to_string(), "30M40N30M");
+        assert_eq!(result.mapq, 27);
+    }
+
+    #[test]
+    fn map_read_spliced_hits() {
+        // The first exon is repeated after the end of the gene
+        let read_mapping_index = test_index(concat!(
+            "GGACTGGCAT",
+            "TTTTATTACACTCAGAAACAGAACTCGGGT",
+            "GTAATTTTGACAGGTCACGCAGAGGCGCGCCCTCCTGAAG",
+            "AGTGCGTGGACACTCGCTATGAATCTCTGA",
+            "CCATGACCTA",
+            "TTTTATTACACTCAGAAACAGAACTCGGGT",
+            "CCATGA"
+        ));
+
+        let read = "TTTTATTACACTCAGAAACAGAACTCGGGTAGTGCGTGGACACTCGCTATGAATCTCTGA";
+
+        let mut options = MapReadOptions::new(10, 6);
+        options.max_hits = 5;
+        options.splicing = Some(SpliceOptions::default());
+
+        /*
+         * Whichever copy of the first exon the seed is placed at, its hits are alternatives to
+         * part of the read rather than to the chain, so none are reported
+         */
+        for seed in 0..8 {
+            options.tie_break = TieBreak::Random { seed };
+            let results = read_mapping_index.map_read_hits(read, None, &options);
+
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].position, 10);
+            assert_eq!(results[0].cigar.to_string(), "30M40N30M");
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn splice_options_panics_on_short_min_intron_length() {
+        SpliceOptions::new(1, 50000, 8);
+    }
+
+    #[test]
+    #[should_panic]
+    fn map_read_options_panics_on_zero_seed_length() {
+        MapReadOptions::new(0, 1);
+    }
+
+    #[test]
+    fn map_read_multiple_contigs() {
+        let read_mapping_index = ReadMappingIndex::new(&[
+            FastaRecord::new(
+                "chromosome",
+                "ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT",
+            ),
+            FastaRecord::new("plasmid", "GGACTGGCATTTTTATTACACTCAGAAACAGAACTCGGGT"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            read_mapping_index.contigs(),
+            &[
+                Contig {
+                    name: String::from("chromosome"),
+                    length: 48,
+                    offset: 0
+                },
+                Contig {
+                    name: String::from("plasmid"),
+                    length: 40,
+                    // Contigs are separated by one base
+                    offset: 49
+                }
+            ]
+        );
+
+        let result = read_mapping_index.map_read("ATCAAATGTAAAAG", 7, 2).unwrap();
+        assert_eq!((result.contig_index, result.position), (0, 7));
+
+        let result = read_mapping_index
+            .map_read("TTTTATTACACTCAGAAACA", 10, 1)
+            .unwrap();
+        assert_eq!((result.contig_index, result.position), (1, 10));
+        assert_eq!(result.cigar.to_string(), "20M");
+    }
+
+    #[test]
+    fn map_read_never_crosses_contigs() {
+        let read_mapping_index = ReadMappingIndex::new(&[
+            FastaRecord::new(
+                "chromosome",
+                "ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT",
+            ),
+            FastaRecord::new("plasmid", "GGACTGGCATTTTTATTACACTCAGAAACAGAACTCGGGT"),
+        ])
+        .unwrap();
+
+        // The end of the chromosome followed by the start of the plasmid
+        let read = "TTACGTCTAATTTTTGGACTGGCATTTTTA";
+
+        // Seeds spanning the join are never found
+        assert_eq!(read_mapping_index.map_read(read, 30, 1), None);
+        assert_eq!(read_mapping_index.map_read(&read[5..25], 20, 1), None);
+
+        let mut options = MapReadOptions::new(10, 3);
+        options.max_mismatches = 10;
+
+        for extension_mode in [
+            ExtensionMode::Naive,
+            ExtensionMode::BandedAlignment { band_width: 5 },
+        ] {
+            options.extension_mode = extension_mode;
+
+            /*
+             * Seeds are taken from the start of the read, which is in the chromosome for the read
+             * and in the plasmid for its reverse complement
+             */
+            for (read, contig_position) in [
+                (read.to_string(), (0, 33)),
+                (reverse_complement(read), (1, 0)),
+            ] {
+                let result = read_mapping_index
+                    .map_read_with_options(&read, &options)
+                    .unwrap();
+                assert_eq!((result.contig_index, result.position), contig_position);
+                assert_eq!(result.match_length, 15);
+            }
+        }
+    }
+
+    #[test]
+    fn map_read_with_ambiguous_reference() {
+        let read_mapping_index = ReadMappingIndex::new(&[
+            FastaRecord::new(
+                "chromosome",
+                "ATACTTTATCAAATGTNNNNNRYRTCTCCTTCGTTTACGTCTAATTTTT",
+            ),
+            FastaRecord::new("plasmid", "ggactggcatKTTTATTACACTCAGAAACAGAACTCGGGT"),
+        ])
+        .unwrap();
+
+        assert_eq!(read_mapping_index.masked_base_count(), 9);
+        assert_eq!(
+            read_mapping_index.ambiguous_spans()[..2],
+            [
+                AmbiguousSpan {
+                    contig_index: 0,
+                    start: 16,
+                    length: 5,
+                    code: 'N'
+                },
+                AmbiguousSpan {
+                    contig_index: 0,
+                    start: 21,
+                    length: 1,
+                    code: 'R'
+                }
+            ]
+        );
+        assert_eq!(read_mapping_index.ambiguous_spans().len(), 5);
+
+        // Lowercase bases are indexed like uppercase ones
+        let result = read_mapping_index.map_read("GGACTGGCAT", 10, 1).unwrap();
+        assert_eq!((result.contig_index, result.position), (1, 0));
+
+        // Masked bases never match, even with mismatches allowed
+        let mut options = MapReadOptions::new(10, 1);
+        options.max_mismatches = 10;
+        let result = read_mapping_index
+            .map_read_with_options("ATACTTTATCAAATGTAAAAGTATCT", &options)
+            .unwrap();
+        assert_eq!(result.cigar.to_string(), "16M10S");
+    }
+
+    #[test]
+    fn builder_sampling_steps() {
+        let genome = "ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTTGGCATTACGTAACGG";
+        let records = [FastaRecord::new("genome", genome)];
+
+        let default_index = ReadMappingIndex::new(&records).unwrap();
+        let sampled_index = ReadMappingIndexBuilder::new()
+            .sampling_steps(SamplingSteps {
+                suffix_array: 2,
+                rank: 4,
             })
+            .reverse_sampling_steps(SamplingSteps {
+                suffix_array: 8,
+                rank: 16,
+            })
+            .build(&records)
+            .unwrap();
+
+        assert_eq!(default_index.build_params(), BuildParams::default());
+        assert_eq!(
+            sampled_index.build_params().forwards,
+            SamplingSteps {
+                suffix_array: 2,
+                rank: 4
+            }
+        );
+        assert_eq!(
+            sampled_index.build_params().reverse,
+            SamplingSteps {
+                suffix_array: 8,
+                rank: 16
+            }
+        );
+
+        // Denser sampling takes more memory, most of all in the more densely sampled forwards index
+        let default_usage = default_index.memory_usage();
+        let sampled_usage = sampled_index.memory_usage();
+        assert!(sampled_usage.total() > default_usage.total());
+        assert!(sampled_usage.forwards.suffix_array > sampled_usage.reverse.suffix_array);
+        assert!(sampled_usage.forwards.rank_checkpoints > sampled_usage.reverse.rank_checkpoints);
+
+        // Sampling only changes how matches are found, not which
+        let mut options = MapReadOptions::new(8, 3);
+        options.max_hits = 5;
+        for start in 0..genome.len() - 20 {
+            let read = &genome[start..start + 20];
+            assert_eq!(
+                sampled_index.map_read_hits(read, None, &options),
+                default_index.map_read_hits(read, None, &options)
+            );
+        }
+    }
+
+    #[test]
+    fn build_rejects_zero_sampling_steps() {
+        let builder = ReadMappingIndexBuilder::new().forwards_sampling_steps(SamplingSteps {
+            suffix_array: 0,
+            rank: 8,
+        });
+        assert_eq!(
+            builder.build(&[]).err(),
+            Some(IndexBuildError::InvalidSuffixArrayStep { step: 0 })
+        );
+
+        let builder = ReadMappingIndexBuilder::new().reverse_sampling_steps(SamplingSteps {
+            suffix_array: 8,
+            rank: 0,
+        });
+        assert_eq!(
+            builder.build(&[]).err(),
+            Some(IndexBuildError::InvalidRankStep { step: 0 })
+        );
+    }
+
+    #[test]
+    fn build_rejects_packed_rank_step() {
+        let builder = ReadMappingIndexBuilder::new()
+            .bwt_storage(BwtStorage::Packed)
+            .reverse_sampling_steps(SamplingSteps {
+                suffix_array: 8,
+                rank: 48,
+            });
+        assert_eq!(
+            builder.build(&[]).err(),
+            Some(IndexBuildError::InvalidRankStep { step: 48 })
         );
     }
 
+    #[test]
+    fn build_rejects_invalid_records() {
+        assert_eq!(
+            ReadMappingIndex::new(&[]).err(),
+            Some(IndexBuildError::NoContigs)
+        );
+
+        for (sequence, position, base) in [
+            ("ACGT*ACGT", 4, '*'),
+            ("ACGTN7", 5, '7'),
+            ("\tACGT", 0, '\t'),
+        ] {
+            let records = [
+                FastaRecord::new("chromosome", "ATACTTTATCAAATGTAAAAG"),
+                FastaRecord::new("plasmid", sequence),
+            ];
+            assert_eq!(
+                ReadMappingIndex::new(&records).err(),
+                Some(IndexBuildError::InvalidBase {
+                    contig_name: "plasmid".to_string(),
+                    position,
+                    base,
+                })
+            );
+        }
+    }
+
     #[test]
     #[should_panic]
     fn map_read_panics_on_invalid_params() {
-        let read_mapping_index =
-            ReadMappingIndex::new("ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT");
+        let read_mapping_index = test_index("ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT");
         read_mapping_index.map_read("ATAC", 5, 4);
     }
 }
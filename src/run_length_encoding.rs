@@ -1,50 +1,51 @@
-use bincode::{Encode, Decode};
 use std::cmp::min;
+use std::io::{self, Write};
 
-#[derive(Debug, Encode, Decode)]
-struct RunLengthEncodingEntry {
-    char: char,
-    count: usize
-}
-
-#[derive(Debug, Encode, Decode)]
-struct RunLengthEncodingCheckpoint {
-    entry_index: usize,
-    offset: usize
-}
+use crate::flat_array::FlatArray;
+use crate::index_file::{IndexFileError, IndexReader, IndexWriter};
 
-#[derive(Debug, Encode, Decode)]
+/*
+ * Each run and checkpoint is split across flat arrays (rather than a `Vec` of structs) so that
+ * they can be used in place from a memory mapped index file
+ */
+#[derive(Debug)]
 pub struct RunLengthEncodedString {
-    seq: Vec<RunLengthEncodingEntry>,
-    index_to_seq_checkpoints: Vec<RunLengthEncodingCheckpoint>, 
+    // Character repeated in each run
+    run_chars: FlatArray<u8>,
+    // Number of times the character is repeated in each run
+    run_counts: FlatArray<usize>,
+    // For every `block_size`th index in the string, the run containing it...
+    checkpoint_entry_indices: FlatArray<usize>,
+    // ...and how far into the run it is
+    checkpoint_offsets: FlatArray<usize>,
     block_size: usize
 }
 
 impl RunLengthEncodedString {
     pub fn new(str: &str, block_size: usize) -> Self {
-        let mut maybe_prev_char: Option<char> = None;
+        let mut maybe_prev_char: Option<u8> = None;
         let mut count = 0;
-        let mut rle_sequence = Vec::new();
+        let mut run_chars = Vec::new();
+        let mut run_counts = Vec::new();
 
-        let mut index_to_seq_checkpoints: Vec<RunLengthEncodingCheckpoint> = Vec::new();
+        let mut checkpoint_entry_indices = Vec::new();
+        let mut checkpoint_offsets = Vec::new();
 
-        for (index, char) in str.chars().enumerate() {
+        for (index, char) in str.bytes().enumerate() {
 
             if let Some(prev_char) = maybe_prev_char { // true on every iteration except the first
 
                 // Terminate the previous run if the character changes
                 if prev_char != char {
-                    rle_sequence.push(RunLengthEncodingEntry { char: prev_char, count });
+                    run_chars.push(prev_char);
+                    run_counts.push(count);
                     count = 0;
                 }
             }
 
-            if index % block_size == 0 {
-                let checkpoint = RunLengthEncodingCheckpoint {
-                    entry_index: rle_sequence.len(),
-                    offset: count
-                };
-                index_to_seq_checkpoints.push(checkpoint);
+            if index.is_multiple_of(block_size) {
+                checkpoint_entry_indices.push(run_chars.len());
+                checkpoint_offsets.push(count);
             }
 
             count += 1;
@@ -53,19 +54,110 @@ impl RunLengthEncodedString {
 
         // TODO: think about this - one character we haven't pushed yet?
         if let Some(prev_char) = maybe_prev_char {
-            rle_sequence.push(RunLengthEncodingEntry { char: prev_char, count });
+            run_chars.push(prev_char);
+            run_counts.push(count);
         }
 
         return RunLengthEncodedString {
-            seq: rle_sequence,
-            index_to_seq_checkpoints,
+            run_chars: run_chars.into(),
+            run_counts: run_counts.into(),
+            checkpoint_entry_indices: checkpoint_entry_indices.into(),
+            checkpoint_offsets: checkpoint_offsets.into(),
             block_size
         };
     }
 
+    // Bytes used by the runs and checkpoints
+    pub fn size_in_bytes(&self) -> usize {
+        return self.run_chars.size_in_bytes()
+            + self.run_counts.size_in_bytes()
+            + self.checkpoint_entry_indices.size_in_bytes()
+            + self.checkpoint_offsets.size_in_bytes();
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut IndexWriter<W>) -> io::Result<()> {
+        writer.write_word(self.block_size)?;
+        writer.write_array(&self.run_chars)?;
+        writer.write_array(&self.run_counts)?;
+        writer.write_array(&self.checkpoint_entry_indices)?;
+        writer.write_array(&self.checkpoint_offsets)?;
+        return Ok(());
+    }
+
+    // Reads a string written by `write_to` for a string of `len` characters
+    pub(crate) fn read_from(reader: &mut IndexReader, len: usize) -> Result<Self, IndexFileError> {
+        let block_size = reader.read_word()?;
+        let run_chars = reader.read_array()?;
+        let run_counts = reader.read_array()?;
+        let checkpoint_entry_indices = reader.read_array()?;
+        let checkpoint_offsets = reader.read_array()?;
+
+        let rle_string = RunLengthEncodedString {
+            run_chars,
+            run_counts,
+            checkpoint_entry_indices,
+            checkpoint_offsets,
+            block_size,
+        };
+
+        /*
+         * Reading every run would page in the whole file, so only the checkpoints are checked,
+         * along with the runs after the last one. `ReadMappingIndex::verify` checks the rest.
+         */
+        let checkpoint_count = len.div_ceil(block_size.max(1));
+        if block_size == 0
+            || rle_string.run_chars.len() != rle_string.run_counts.len()
+            || rle_string.checkpoint_entry_indices.len() != checkpoint_count
+            || rle_string.checkpoint_offsets.len() != checkpoint_count
+            || !rle_string.checkpoints_consistent(len)
+        {
+            return Err(IndexFileError::Corrupt(
+                "inconsistent run-length encoded BWT".to_string(),
+            ));
+        }
+
+        return Ok(rle_string);
+    }
+
+    /*
+     * Whether the checkpoints start at the first run and each is within a run, in order, and
+     * whether the runs from the last checkpoint end `len` characters into the string
+     */
+    fn checkpoints_consistent(&self, len: usize) -> bool {
+        let checkpoints = self
+            .checkpoint_entry_indices
+            .iter()
+            .zip(self.checkpoint_offsets.iter());
+
+        let mut previous_checkpoint = (0, 0);
+        for (&entry_index, &offset) in checkpoints {
+            match self.run_counts.get(entry_index) {
+                Some(&run_count)
+                    if offset < run_count && (entry_index, offset) >= previous_checkpoint => {}
+                _ => return false,
+            }
+            previous_checkpoint = (entry_index, offset);
+        }
+
+        let Some(last_checkpoint_index) = self.checkpoint_entry_indices.len().checked_sub(1) else {
+            return self.run_counts.is_empty();
+        };
+        if (self.checkpoint_entry_indices[0], self.checkpoint_offsets[0]) != (0, 0) {
+            return false;
+        }
+
+        // Number of characters from the last checkpoint to the end of the string
+        let (last_entry_index, last_offset) = previous_checkpoint;
+        let remaining_count = self.run_counts[last_entry_index..]
+            .iter()
+            .try_fold(0usize, |count, &run_count| count.checked_add(run_count));
+        return remaining_count.and_then(|count| count.checked_sub(last_offset))
+            == Some(len - last_checkpoint_index * self.block_size);
+    }
+
     // Returns the number of matches in the RLE encoded string in the interval (checkpoint_index, target_index]
     pub fn count_matches_from_checkpoint(&self, target_char: char, checkpoint_index: usize, target_index: usize) -> usize {        
-        if checkpoint_index % self.block_size != 0 {
+        if !checkpoint_index.is_multiple_of(self.block_size) {
             panic!("Index {} is not a checkpoint for block size {}", checkpoint_index, self.block_size);
         }
 
@@ -75,26 +167,28 @@ impl RunLengthEncodedString {
 
         let mut match_count = 0;
 
-        let checkpoint = &self.index_to_seq_checkpoints[checkpoint_index / self.block_size];    
+        let checkpoint_entry_index =
+            self.checkpoint_entry_indices[checkpoint_index / self.block_size];
+        let checkpoint_offset = self.checkpoint_offsets[checkpoint_index / self.block_size];
 
         // First block needs special care since the checkpoint index may be somewhere in the 
         // run - not neccessarily at the front
-        let first_rle_entry = &self.seq[checkpoint.entry_index];
+        let first_run_count = self.run_counts[checkpoint_entry_index];
 
-        let following_entry_start_index = checkpoint_index + first_rle_entry.count - checkpoint.offset;
+        let following_entry_start_index = checkpoint_index + first_run_count - checkpoint_offset;
 
-        if first_rle_entry.char == target_char {
+        if self.run_chars[checkpoint_entry_index] as char == target_char {
             if target_index >= following_entry_start_index {
                 /*
                  * Case 1: `target_index` is not in the first run
                  * 
                  * Number of chars left in the run after the checkpoint is
-                 * first_rle_entry.count - checkpoint.offset
+                 * first_run_count - checkpoint_offset
                  *   
                  * -1 since we don't want to count the first char (counted by 
                  * partial rank lookup already)
                  */
-                match_count += first_rle_entry.count - checkpoint.offset - 1
+                match_count += first_run_count - checkpoint_offset - 1
             } else {
                 /*
                  * Case 2: `target_index` is in the first run
@@ -113,12 +207,12 @@ impl RunLengthEncodedString {
 
         // index in the string at start of the next run
         let mut current_str_index = following_entry_start_index;
-        let mut rle_seq_index = checkpoint.entry_index + 1;
+        let mut rle_seq_index = checkpoint_entry_index + 1;
 
         while current_str_index <= target_index {
-            let rle_entry = &self.seq[rle_seq_index];
+            let run_count = self.run_counts[rle_seq_index];
 
-            if rle_entry.char == target_char {
+            if self.run_chars[rle_seq_index] as char == target_char {
                 /*
                  * Use min in case target index in middle of RLE entry eg when matching on 'A':
                  * 012345
@@ -127,10 +221,10 @@ impl RunLengthEncodedString {
                  * when target_index = 3, we don't want the count from the entry (6)
                  * +1 needed to convert from indicies to counts
                  */
-                match_count += min(rle_entry.count, target_index - current_str_index + 1);
+                match_count += min(run_count, target_index - current_str_index + 1);
             }
 
-            current_str_index += rle_entry.count;
+            current_str_index += run_count;
 
             // Consider next RLE entry on next iteration
             rle_seq_index += 1;
@@ -141,9 +235,7 @@ impl RunLengthEncodedString {
 
     pub fn get_char_from_position(&self, target_index: usize) -> char {
         let checkpoint_index = target_index / self.block_size;
-        let checkpoint = &self.index_to_seq_checkpoints[checkpoint_index];
-
-        let mut current_entry_index = checkpoint.entry_index;
+        let mut current_entry_index = self.checkpoint_entry_indices[checkpoint_index];
 
         /*
          * Starting index of the run the checkpoint points to
@@ -153,18 +245,18 @@ impl RunLengthEncodedString {
          * C C G G T T
          * ^ 1st checkpoint
          *       ^ 2nd checkpoint has entry index 1, offset 1
-         * checkpoint_index * self.block_size - checkpoint.offset = 1 * 3 - 1 = 2 for the 
+         * checkpoint_index * self.block_size - checkpoint offset = 1 * 3 - 1 = 2 for the
          * index where the run starts
-         */ 
-        let mut current_index = checkpoint_index * self.block_size - checkpoint.offset;
+         */
+        let mut current_index =
+            checkpoint_index * self.block_size - self.checkpoint_offsets[checkpoint_index];
 
         // Scan along the RLE one run at a time
         loop {
-            let entry = &self.seq[current_entry_index];
-            current_index += entry.count;
+            current_index += self.run_counts[current_entry_index];
 
             if current_index > target_index {
-                return entry.char
+                return self.run_chars[current_entry_index] as char;
             }
 
             current_entry_index += 1;
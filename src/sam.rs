@@ -0,0 +1,333 @@
+use std::io::{self, Write};
+
+use crate::fastq_parsing::FastqRecord;
+use crate::paired_end::{Mate, MateInfo};
+use crate::read_mapping_index::{reverse_complement, Contig, MapReadResult, Strand};
+
+// Bitwise FLAG values used in SAM records
+pub const FLAG_PAIRED: u16 = 0x1;
+pub const FLAG_PROPER_PAIR: u16 = 0x2;
+pub const FLAG_UNMAPPED: u16 = 0x4;
+pub const FLAG_MATE_UNMAPPED: u16 = 0x8;
+pub const FLAG_REVERSE_STRAND: u16 = 0x10;
+pub const FLAG_MATE_REVERSE_STRAND: u16 = 0x20;
+pub const FLAG_FIRST_MATE: u16 = 0x40;
+pub const FLAG_SECOND_MATE: u16 = 0x80;
+pub const FLAG_SECONDARY: u16 = 0x100;
+
+const SAM_VERSION: &str = "1.6";
+
+// Writes mapped reads in the SAM format, for use with samtools, IGV etc
+pub struct SamWriter<'a, W: Write> {
+    writer: W,
+    contigs: &'a [Contig],
+}
+
+impl<'a, W: Write> SamWriter<'a, W> {
+    // Writes the header, with an @SQ line for each contig in the index
+    pub fn new(mut writer: W, contigs: &'a [Contig]) -> io::Result<Self> {
+        writer.write_all(sam_header(contigs, "unsorted").as_bytes())?;
+        return Ok(SamWriter { writer, contigs });
+    }
+
+    // Writes a record for the read, which is unmapped if `result` is `None`
+    pub fn write_record(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+    ) -> io::Result<()> {
+        return self.write_sam_record(record, result, None);
+    }
+
+    // Writes a record for one mate of a paired read
+    pub fn write_mate(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+        mate_info: &MateInfo,
+    ) -> io::Result<()> {
+        return self.write_sam_record(record, result, Some(mate_info));
+    }
+
+    fn write_sam_record(
+        &mut self,
+        record: &FastqRecord,
+        result: Option<&MapReadResult>,
+        mate_info: Option<&MateInfo>,
+    ) -> io::Result<()> {
+        let flag = sam_flag(result, mate_info);
+        let (placement, mate_placement) = placements(result, mate_info);
+
+        let contig_name = |placement: Placement| match placement {
+            Some((contig_index, _)) => self.contigs[contig_index].name.as_str(),
+            None => "*",
+        };
+        // SAM positions are 1-based, with 0 for no position
+        let sam_position = |placement: Placement| match placement {
+            Some((_, position)) => position + 1,
+            None => 0,
+        };
+
+        let mate_contig_name = match (placement, mate_placement) {
+            (Some((contig_index, _)), Some((mate_contig_index, _)))
+                if contig_index == mate_contig_index =>
+            {
+                "="
+            }
+            _ => contig_name(mate_placement),
+        };
+        let template_length = mate_info.map_or(0, |mate_info| mate_info.template_length);
+
+        write!(
+            self.writer,
+            "{}\t{flag}\t{}\t{}\t",
+            record.name,
+            contig_name(placement),
+            sam_position(placement)
+        )?;
+
+        let Some(result) = result else {
+            return writeln!(
+                self.writer,
+                "0\t*\t{mate_contig_name}\t{}\t{template_length}\t{}\t{}",
+                sam_position(mate_placement),
+                record.seq,
+                sam_quality(&record.qual)
+            );
+        };
+
+        let (seq, qual) = oriented_seq_and_qual(record, result.strand);
+
+        return writeln!(
+            self.writer,
+            "{}\t{}\t{mate_contig_name}\t{}\t{template_length}\t{seq}\t{}\tAS:i:{}\tNM:i:{}",
+            result.mapq,
+            result.cigar,
+            sam_position(mate_placement),
+            sam_quality(&qual),
+            result.alignment_score,
+            result.edit_distance
+        );
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        return self.writer.flush();
+    }
+}
+
+// Header with an @SQ line for each contig in the index, shared with BAM
+pub(crate) fn sam_header(contigs: &[Contig], sort_order: &str) -> String {
+    let mut header = format!("@HD\tVN:{SAM_VERSION}\tSO:{sort_order}\n");
+
+    for contig in contigs {
+        header += &format!("@SQ\tSN:{}\tLN:{}\n", contig.name, contig.length);
+    }
+
+    header += &format!(
+        "@PG\tID:{0}\tPN:{0}\tVN:{1}\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+
+    return header;
+}
+
+// FLAG for a read, and its mate if it is paired, shared with BAM
+pub(crate) fn sam_flag(result: Option<&MapReadResult>, mate_info: Option<&MateInfo>) -> u16 {
+    let mut flag = 0;
+
+    match result {
+        Some(result) => {
+            if result.strand == Strand::Reverse {
+                flag |= FLAG_REVERSE_STRAND;
+            }
+            if result.secondary {
+                flag |= FLAG_SECONDARY;
+            }
+        }
+        None => flag |= FLAG_UNMAPPED,
+    }
+
+    if let Some(mate_info) = mate_info {
+        flag |= FLAG_PAIRED;
+        flag |= match mate_info.mate {
+            Mate::First => FLAG_FIRST_MATE,
+            Mate::Second => FLAG_SECOND_MATE,
+        };
+        if mate_info.proper_pair {
+            flag |= FLAG_PROPER_PAIR;
+        }
+
+        match mate_info.mate_result {
+            Some(mate_result) if mate_result.strand == Strand::Reverse => {
+                flag |= FLAG_MATE_REVERSE_STRAND
+            }
+            Some(_) => {}
+            None => flag |= FLAG_MATE_UNMAPPED,
+        }
+    }
+
+    return flag;
+}
+
+// Contig index and position within it of a record, if it has one
+pub(crate) type Placement = Option<(usize, usize)>;
+
+/*
+ * Contig index and position of the read and of its mate, if known, shared with BAM. As the SAM
+ * spec recommends, an unmapped read with a mapped mate is placed at its mate, so that the mates
+ * sort together.
+ */
+pub(crate) fn placements(
+    result: Option<&MapReadResult>,
+    mate_info: Option<&MateInfo>,
+) -> (Placement, Placement) {
+    let placement = |result: &MapReadResult| (result.contig_index, result.position);
+
+    let Some(mate_info) = mate_info else {
+        return (result.map(placement), None);
+    };
+
+    let mate_placement = mate_info.mate_result.map(placement);
+    let read_placement = result.map(placement).or(mate_placement);
+
+    return (read_placement, mate_placement.or(read_placement));
+}
+
+// SAM and BAM store reads from the reverse strand as they appear in the forwards genome
+pub(crate) fn oriented_seq_and_qual(record: &FastqRecord, strand: Strand) -> (String, String) {
+    match strand {
+        Strand::Forward => (record.seq.clone(), record.qual.clone()),
+        Strand::Reverse => (
+            reverse_complement(&record.seq),
+            record.qual.chars().rev().collect(),
+        ),
+    }
+}
+
+// SAM uses `*` when the qualities are unknown
+fn sam_quality(qual: &str) -> &str {
+    if qual.is_empty() {
+        return "*";
+    }
+    return qual;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fasta_parsing::FastaRecord;
+    use crate::paired_end::InsertSizeDistribution;
+    use crate::read_mapping_index::{MapReadOptions, ReadMappingIndex};
+
+    fn fastq_record(name: &str, seq: &str, qual: &str) -> FastqRecord {
+        return FastqRecord {
+            name: String::from(name),
+            comment: None,
+            seq: String::from(seq),
+            qual: String::from(qual),
+        };
+    }
+
+    #[test]
+    fn write_sam() {
+        let read_mapping_index = ReadMappingIndex::new(&[
+            FastaRecord::new(
+                "chromosome",
+                "ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT",
+            ),
+            FastaRecord::new("plasmid", "GGACTGGCATTTTTATTACACTCAGAAACAGAACTCGGGT"),
+        ])
+        .unwrap();
+
+        let reads = [
+            fastq_record("forward", "TTATTACACTCAGAA", "IIIIIIIIIIIIII#"),
+            fastq_record("reverse", "AGACGTAAACGAAGG", "#IIIIIIIIIIIIII"),
+            fastq_record("unmapped", "CCCCCCCCCCCCCCC", "IIIIIIIIIIIIIII"),
+        ];
+
+        let mut output = Vec::new();
+        let mut sam_writer = SamWriter::new(&mut output, read_mapping_index.contigs()).unwrap();
+        for read in &reads {
+            let result = read_mapping_index.map_read(&read.seq, 10, 1);
+            sam_writer.write_record(read, result.as_ref()).unwrap();
+        }
+
+        let sam = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = sam.lines().collect();
+
+        assert_eq!(lines[0], "@HD\tVN:1.6\tSO:unsorted");
+        assert_eq!(lines[1], "@SQ\tSN:chromosome\tLN:48");
+        assert_eq!(lines[2], "@SQ\tSN:plasmid\tLN:40");
+        assert!(lines[3].starts_with("@PG\tID:read-mapping"));
+        assert_eq!(
+            lines[4],
+            "forward\t0\tplasmid\t13\t60\t15M\t*\t0\t0\tTTATTACACTCAGAA\tIIIIIIIIIIIIII#\tAS:i:15\tNM:i:0"
+        );
+        assert_eq!(
+            lines[5],
+            "reverse\t16\tchromosome\t27\t60\t15M\t*\t0\t0\tCCTTCGTTTACGTCT\tIIIIIIIIIIIIII#\tAS:i:15\tNM:i:0"
+        );
+        assert_eq!(
+            lines[6],
+            "unmapped\t4\t*\t0\t0\t*\t*\t0\t0\tCCCCCCCCCCCCCCC\tIIIIIIIIIIIIIII"
+        );
+    }
+
+    #[test]
+    fn write_sam_pairs() {
+        let read_mapping_index = ReadMappingIndex::new(&[FastaRecord::new(
+            "chromosome",
+            "ATACTTTATCAAATGTAAAAGTATCTCCTTCGTTTACGTCTAATTTTT",
+        )])
+        .unwrap();
+        let options = MapReadOptions::new(10, 1);
+        let insert_sizes = InsertSizeDistribution {
+            mean: 40.0,
+            std_dev: 2.0,
+        };
+
+        let pairs = [
+            (
+                fastq_record("proper", "ATACTTTATCAAATG", "IIIIIIIIIIIIIII"),
+                fastq_record("proper", "AGACGTAAACGAAGG", "IIIIIIIIIIIIIII"),
+            ),
+            (
+                fastq_record("unmapped_mate", "ATACTTTATCAAATG", "IIIIIIIIIIIIIII"),
+                fastq_record("unmapped_mate", "CCCCCCCCCCCCCCC", "IIIIIIIIIIIIIII"),
+            ),
+        ];
+
+        let mut output = Vec::new();
+        let mut sam_writer = SamWriter::new(&mut output, read_mapping_index.contigs()).unwrap();
+        for (first, second) in &pairs {
+            let paired_result = read_mapping_index.map_pair(first, second, &options, &insert_sizes);
+            for (record, mate) in [(first, Mate::First), (second, Mate::Second)] {
+                let result = paired_result.result(mate);
+                let mate_info = paired_result.mate_info(mate);
+                sam_writer.write_mate(record, result, &mate_info).unwrap();
+            }
+        }
+
+        let sam = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = sam.lines().filter(|line| !line.starts_with('@')).collect();
+
+        assert_eq!(
+            lines[0],
+            "proper\t99\tchromosome\t1\t60\t15M\t=\t27\t41\tATACTTTATCAAATG\tIIIIIIIIIIIIIII\tAS:i:15\tNM:i:0"
+        );
+        assert_eq!(
+            lines[1],
+            "proper\t147\tchromosome\t27\t60\t15M\t=\t1\t-41\tCCTTCGTTTACGTCT\tIIIIIIIIIIIIIII\tAS:i:15\tNM:i:0"
+        );
+        // The unmapped mate is placed at its mapped mate
+        assert_eq!(
+            lines[2],
+            "unmapped_mate\t73\tchromosome\t1\t60\t15M\t=\t1\t0\tATACTTTATCAAATG\tIIIIIIIIIIIIIII\tAS:i:15\tNM:i:0"
+        );
+        assert_eq!(
+            lines[3],
+            "unmapped_mate\t133\tchromosome\t1\t0\t*\t=\t1\t0\tCCCCCCCCCCCCCCC\tIIIIIIIIIIIIIII"
+        );
+    }
+}
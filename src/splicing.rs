@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::read_mapping_index::{Contig, MapReadResult};
+
+// Shortest intron with room for a donor and an acceptor motif, of 2 bases each
+pub const MIN_INTRON_LENGTH: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct SpliceOptions {
+    // Gaps between seed hits shorter than this are not considered introns
+    min_intron_length: usize,
+    max_intron_length: usize,
+    // Score penalty for a junction without a canonical GT-AG splice motif
+    non_canonical_penalty: isize,
+}
+
+impl SpliceOptions {
+    // Panics if `min_intron_length` is less than `MIN_INTRON_LENGTH`
+    pub fn new(
+        min_intron_length: usize,
+        max_intron_length: usize,
+        non_canonical_penalty: isize,
+    ) -> Self {
+        assert!(min_intron_length >= MIN_INTRON_LENGTH);
+
+        return SpliceOptions {
+            min_intron_length,
+            max_intron_length,
+            non_canonical_penalty,
+        };
+    }
+
+    pub fn min_intron_length(&self) -> usize {
+        return self.min_intron_length;
+    }
+
+    pub fn max_intron_length(&self) -> usize {
+        return self.max_intron_length;
+    }
+
+    pub fn non_canonical_penalty(&self) -> isize {
+        return self.non_canonical_penalty;
+    }
+}
+
+impl Default for SpliceOptions {
+    fn default() -> Self {
+        return SpliceOptions::new(20, 50000, 8);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SpliceMotif {
+    // GT...AG in the forwards genome, so the transcript is on the forwards strand
+    GtAg,
+    // Reverse complement of GT...AG, so the transcript is on the reverse strand
+    CtAc,
+    NonCanonical,
+}
+
+impl SpliceMotif {
+    // Classifies an intron by its first two (donor) and last two (acceptor) bases
+    pub fn classify(donor: &str, acceptor: &str) -> Self {
+        match (donor, acceptor) {
+            ("GT", "AG") => SpliceMotif::GtAg,
+            ("CT", "AC") => SpliceMotif::CtAc,
+            _ => SpliceMotif::NonCanonical,
+        }
+    }
+
+    pub fn is_canonical(&self) -> bool {
+        return *self != SpliceMotif::NonCanonical;
+    }
+
+    // Strand of the transcript implied by the motif, as written in BED files
+    pub fn bed_strand(&self) -> char {
+        match self {
+            SpliceMotif::GtAg => '+',
+            SpliceMotif::CtAc => '-',
+            SpliceMotif::NonCanonical => '.',
+        }
+    }
+}
+
+// An intron in the forwards genome spanned by a spliced read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Junction {
+    // Index into `ReadMappingIndex::contigs`
+    pub contig_index: usize,
+    // First base of the intron within the contig
+    pub start: usize,
+    // Exclusive
+    pub end: usize,
+    pub motif: SpliceMotif,
+}
+
+// Counts the reads supporting each junction
+#[derive(Debug, Default)]
+pub struct JunctionTable {
+    read_counts: HashMap<Junction, usize>,
+}
+
+impl JunctionTable {
+    pub fn new() -> Self {
+        return JunctionTable::default();
+    }
+
+    pub fn add_read(&mut self, result: &MapReadResult) {
+        for junction in &result.junctions {
+            *self.read_counts.entry(*junction).or_insert(0) += 1;
+        }
+    }
+
+    pub fn read_count(&self, junction: &Junction) -> usize {
+        return *self.read_counts.get(junction).unwrap_or(&0);
+    }
+
+    /*
+     * Writes a BED line for each junction, sorted by position. The BED score column holds the
+     * number of supporting reads, and the strand column the strand implied by the splice motif.
+     */
+    pub fn write_bed<W: Write>(&self, writer: &mut W, contigs: &[Contig]) -> io::Result<()> {
+        let mut junctions: Vec<_> = self.read_counts.iter().collect();
+        junctions.sort();
+
+        for (junction_number, (junction, read_count)) in junctions.into_iter().enumerate() {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\tJUNC{:08}\t{}\t{}",
+                contigs[junction.contig_index].name,
+                junction.start,
+                junction.end,
+                junction_number + 1,
+                read_count,
+                junction.motif.bed_strand()
+            )?;
+        }
+
+        return Ok(());
+    }
+}
@@ -0,0 +1,318 @@
+use std::io::{self, Write};
+
+use crate::flat_array::FlatArray;
+use crate::index_file::{IndexFileError, IndexReader, IndexWriter};
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+// Average number of set bits in each bucket of a `SparseBitVector`
+const SET_BITS_PER_BUCKET: usize = 8;
+
+/*
+ * Bit vector supporting rank queries, for when few of its bits are set. The bit vector is split
+ * into buckets of 2^`bucket_bits` bits, and the number of set bits before each bucket is stored, so
+ * a set bit only needs its offset within its bucket. Buckets hold around 8 set bits, so each set
+ * bit takes about log2(8 * len / set bits) bits, and bits which aren't set take no space.
+ */
+#[derive(Debug)]
+pub struct SparseBitVector {
+    // Number of set bits before each bucket, followed by the total
+    bucket_ranks: PackedArray,
+    // Offset of each set bit within its bucket, in order
+    offsets: PackedArray,
+    bucket_bits: usize,
+    len: usize,
+}
+
+impl SparseBitVector {
+    // Bit vector of `len` bits, with the bits at `set_bits` set, which must be in increasing order
+    pub fn new(len: usize, set_bits: impl IntoIterator<Item = usize>) -> Self {
+        let set_bits: Vec<usize> = set_bits.into_iter().collect();
+        let average_gap = len / set_bits.len().max(1);
+        let bucket_bits = (average_gap.saturating_mul(SET_BITS_PER_BUCKET))
+            .max(1)
+            .ilog2() as usize;
+
+        let mut bucket_ranks = Vec::with_capacity(bucket_count(len, bucket_bits) + 1);
+        let mut offsets = Vec::with_capacity(set_bits.len());
+        for (rank, &bit) in set_bits.iter().enumerate() {
+            assert!(bit < len);
+            assert!(rank == 0 || set_bits[rank - 1] < bit);
+
+            while bucket_ranks.len() <= bit >> bucket_bits {
+                bucket_ranks.push(rank);
+            }
+            offsets.push(bit & offset_mask(bucket_bits));
+        }
+        bucket_ranks.resize(bucket_count(len, bucket_bits) + 1, set_bits.len());
+
+        return SparseBitVector {
+            bucket_ranks: PackedArray::new(&bucket_ranks),
+            offsets: PackedArray::new(&offsets),
+            bucket_bits,
+            len,
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    pub fn count_ones(&self) -> usize {
+        return self.offsets.len();
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        return self.rank_if_set(index).is_some();
+    }
+
+    // Number of set bits before `index`, which must be within the bit vector
+    pub fn rank(&self, index: usize) -> usize {
+        return self.find(index).0;
+    }
+
+    // Number of set bits before `index` if its bit is set, so locating a row takes one lookup
+    pub fn rank_if_set(&self, index: usize) -> Option<usize> {
+        let (rank, is_set) = self.find(index);
+        return is_set.then_some(rank);
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        return self.bucket_ranks.size_in_bytes() + self.offsets.size_in_bytes();
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut IndexWriter<W>) -> io::Result<()> {
+        writer.write_word(self.len)?;
+        writer.write_word(self.bucket_bits)?;
+        self.bucket_ranks.write_to(writer)?;
+        self.offsets.write_to(writer)?;
+        return Ok(());
+    }
+
+    pub(crate) fn read_from(reader: &mut IndexReader) -> Result<Self, IndexFileError> {
+        let len = reader.read_word()?;
+        let bucket_bits = reader.read_word()?;
+        let bucket_ranks = PackedArray::read_from(reader)?;
+        let offsets = PackedArray::read_from(reader)?;
+
+        let bit_vector = SparseBitVector {
+            bucket_ranks,
+            offsets,
+            bucket_bits,
+            len,
+        };
+
+        // Offsets beyond their bucket would only give wrong ranks, so only the ranks are checked
+        if bucket_bits >= WORD_BITS
+            || bit_vector.bucket_ranks.len() != bucket_count(len, bucket_bits) + 1
+            || bit_vector.bucket_ranks.get(0) != 0
+            || bit_vector
+                .bucket_ranks
+                .get(bit_vector.bucket_ranks.len() - 1)
+                != bit_vector.offsets.len()
+            || (1..bit_vector.bucket_ranks.len()).any(|bucket| {
+                return bit_vector.bucket_ranks.get(bucket)
+                    < bit_vector.bucket_ranks.get(bucket - 1);
+            })
+        {
+            return Err(IndexFileError::Corrupt(
+                "inconsistent sparse bit vector".to_string(),
+            ));
+        }
+
+        return Ok(bit_vector);
+    }
+
+    // Number of set bits before `index`, and whether its bit is set
+    fn find(&self, index: usize) -> (usize, bool) {
+        assert!(index < self.len);
+
+        let bucket = index >> self.bucket_bits;
+        let offset = index & offset_mask(self.bucket_bits);
+        let bucket_end = self.bucket_ranks.get(bucket + 1);
+
+        // Buckets only hold a few set bits, so they're scanned rather than searched
+        let mut rank = self.bucket_ranks.get(bucket);
+        while rank < bucket_end && self.offsets.get(rank) < offset {
+            rank += 1;
+        }
+
+        return (rank, rank < bucket_end && self.offsets.get(rank) == offset);
+    }
+}
+
+// Buckets of 2^`bucket_bits` bits needed to hold `len` bits, with one more if `len` fills the last
+fn bucket_count(len: usize, bucket_bits: usize) -> usize {
+    return (len >> bucket_bits) + 1;
+}
+
+fn offset_mask(bucket_bits: usize) -> usize {
+    return (1 << bucket_bits) - 1;
+}
+
+/*
+ * Array of integers packed into as few bits each as the largest needs, so an array of integers
+ * below n takes ceil(log2 n) bits per element rather than 64
+ */
+#[derive(Debug)]
+pub struct PackedArray {
+    words: FlatArray<u64>,
+    bits_per_element: usize,
+    len: usize,
+}
+
+impl PackedArray {
+    pub fn new(values: &[usize]) -> Self {
+        let max_value = values.iter().copied().max().unwrap_or(0);
+        let bits_per_element = (WORD_BITS - max_value.leading_zeros() as usize).max(1);
+
+        let mut words = vec![0u64; (values.len() * bits_per_element).div_ceil(WORD_BITS)];
+        for (index, &value) in values.iter().enumerate() {
+            let bit = index * bits_per_element;
+            let (word_index, offset) = (bit / WORD_BITS, bit % WORD_BITS);
+
+            words[word_index] |= (value as u64) << offset;
+            // Values may straddle 2 words
+            if offset + bits_per_element > WORD_BITS {
+                words[word_index + 1] |= (value as u64) >> (WORD_BITS - offset);
+            }
+        }
+
+        return PackedArray {
+            words: words.into(),
+            bits_per_element,
+            len: values.len(),
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    pub fn get(&self, index: usize) -> usize {
+        assert!(index < self.len);
+
+        let bit = index * self.bits_per_element;
+        let (word_index, offset) = (bit / WORD_BITS, bit % WORD_BITS);
+
+        let mut value = self.words[word_index] >> offset;
+        if offset + self.bits_per_element > WORD_BITS {
+            value |= self.words[word_index + 1] << (WORD_BITS - offset);
+        }
+
+        if self.bits_per_element < WORD_BITS {
+            value &= (1 << self.bits_per_element) - 1;
+        }
+        return value as usize;
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        return self.words.size_in_bytes();
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut IndexWriter<W>) -> io::Result<()> {
+        writer.write_word(self.len)?;
+        writer.write_word(self.bits_per_element)?;
+        writer.write_array(&self.words)?;
+        return Ok(());
+    }
+
+    pub(crate) fn read_from(reader: &mut IndexReader) -> Result<Self, IndexFileError> {
+        let len = reader.read_word()?;
+        let bits_per_element = reader.read_word()?;
+        let words = reader.read_array()?;
+
+        let packed_array = PackedArray {
+            words,
+            bits_per_element,
+            len,
+        };
+
+        if bits_per_element == 0
+            || bits_per_element > WORD_BITS
+            || Some(packed_array.words.len())
+                != len
+                    .checked_mul(bits_per_element)
+                    .map(|bits| bits.div_ceil(WORD_BITS))
+        {
+            return Err(IndexFileError::Corrupt(
+                "inconsistent packed array".to_string(),
+            ));
+        }
+
+        return Ok(packed_array);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparse_bit_vector() {
+        // Several set bits in some buckets and none in others, with the last bit set
+        let len = 5000;
+        let set_bits: Vec<usize> = (0..len)
+            .filter(|bit| bit % 97 == 0 || (1000..1010).contains(bit) || *bit == len - 1)
+            .collect();
+        let bit_vector = SparseBitVector::new(len, set_bits.iter().copied());
+
+        assert_eq!(bit_vector.len(), len);
+        assert_eq!(bit_vector.count_ones(), set_bits.len());
+        for index in 0..len {
+            let rank = set_bits.iter().filter(|&&bit| bit < index).count();
+            assert_eq!(bit_vector.get(index), set_bits.contains(&index));
+            assert_eq!(bit_vector.rank(index), rank);
+            assert_eq!(
+                bit_vector.rank_if_set(index),
+                set_bits.contains(&index).then_some(rank)
+            );
+        }
+
+        // Under a quarter of the 625 bytes of a dense bit vector
+        assert!(bit_vector.size_in_bytes() * 4 < len / 8);
+
+        let empty = SparseBitVector::new(10, []);
+        assert_eq!(empty.count_ones(), 0);
+        assert!(!empty.get(9));
+        assert_eq!(empty.rank(9), 0);
+
+        let full = SparseBitVector::new(10, 0..10);
+        assert_eq!(full.rank(9), 9);
+        assert!(full.get(9));
+    }
+
+    #[test]
+    fn packed_array() {
+        let values: Vec<usize> = (0..300).map(|value| value * 37 % 1000).collect();
+        let packed_array = PackedArray::new(&values);
+
+        assert_eq!(packed_array.len(), values.len());
+        for (index, &value) in values.iter().enumerate() {
+            assert_eq!(packed_array.get(index), value);
+        }
+
+        // 10 bits for each value below 1000
+        assert_eq!(
+            packed_array.size_in_bytes(),
+            (300 * 10usize).div_ceil(64) * 8
+        );
+
+        let full_width = PackedArray::new(&[usize::MAX, 0, 1, usize::MAX]);
+        assert_eq!(full_width.get(0), usize::MAX);
+        assert_eq!(full_width.get(2), 1);
+        assert_eq!(full_width.get(3), usize::MAX);
+
+        let zeros = PackedArray::new(&[0, 0, 0]);
+        assert_eq!(zeros.get(1), 0);
+        assert!(PackedArray::new(&[]).is_empty());
+    }
+}